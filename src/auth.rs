@@ -0,0 +1,164 @@
+use std::{collections::HashMap, fs, io, path::Path};
+
+use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use log::warn;
+use thiserror::Error;
+
+use crate::smtp::EmailAddress;
+
+/// User authenticated on an SMTP session
+#[derive(Debug, Clone)]
+pub struct User {
+    pub name: String,
+    pub addresses: Vec<String>
+}
+
+impl User {
+    /// Whether the user may use this address as sender, the null sender being allowed for everyone
+    pub fn owns(&self, address: &EmailAddress) -> bool {
+        *address == EmailAddress::Null || self.addresses.iter()
+            .any(|it| it.eq_ignore_ascii_case(address.address()))
+    }
+}
+
+/// Restriction applied to MAIL FROM addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SenderPolicy {
+    /// Any sender address is accepted
+    Any,
+    /// Authenticated users may only use their own addresses, and addresses
+    /// of users may not be used without authentication
+    OwnAddresses
+}
+
+pub trait AuthBackend: Send + Sync {
+    /// Check user credentials, returning the user if they are valid
+    fn authenticate(&self, username: &str, password: &str) -> Option<User>;
+
+    /// Whether an address belongs to one of the users
+    fn is_user_address(&self, address: &EmailAddress) -> bool;
+}
+
+#[derive(Debug, Error)]
+pub enum CredentialsFileError {
+    #[error("Failed to read credentials file : {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid credentials line {0}")]
+    InvalidLine(usize),
+    #[error("Invalid password hash for user {0} : {1}")]
+    InvalidHash(String, argon2::password_hash::Error)
+}
+
+/// Credentials stored in a local file with one user per line :
+/// `username:argon2-phc-hash:address1,address2`
+///
+/// Empty lines and lines starting with `#` are ignored.
+pub struct FileAuthBackend {
+    users: HashMap<String, (String, User)>,
+    /// Hash verified for unknown users so that they take as long to refuse as wrong passwords
+    dummy_hash: String
+}
+
+const DUMMY_SALT: &str = "ZGRlbGl2ZXJ5LWR1bW15";
+
+impl FileAuthBackend {
+    pub fn load(path: &Path) -> Result<Self, CredentialsFileError> {
+        let mut users = HashMap::new();
+
+        for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.splitn(3, ':');
+            let (Some(name), Some(hash)) = (fields.next(), fields.next()) else {
+                return Err(CredentialsFileError::InvalidLine(i+1));
+            };
+
+            if let Err(e) = PasswordHash::new(hash) {
+                return Err(CredentialsFileError::InvalidHash(name.to_owned(), e));
+            }
+
+            let addresses = fields.next()
+                .map(|it| it.split(',')
+                    .map(|it| it.trim().to_owned())
+                    .filter(|it| !it.is_empty())
+                    .collect())
+                .unwrap_or_default();
+
+            users.insert(name.to_owned(), (hash.to_owned(), User { name: name.to_owned(), addresses }));
+        }
+
+        let dummy_hash = Argon2::default()
+            .hash_password(b"", &SaltString::from_b64(DUMMY_SALT).expect("Invalid dummy salt"))
+            .expect("Failed to hash dummy password")
+            .to_string();
+
+        Ok(Self { users, dummy_hash })
+    }
+}
+
+impl AuthBackend for FileAuthBackend {
+    fn authenticate(&self, username: &str, password: &str) -> Option<User> {
+        let (hash, user) = match self.users.get(username) {
+            Some((hash, user)) => (hash.as_str(), Some(user)),
+            None => (self.dummy_hash.as_str(), None)
+        };
+
+        let hash = PasswordHash::new(hash)
+            .inspect_err(|e| warn!("Invalid password hash for user {username} : {e}"))
+            .ok()?;
+
+        Argon2::default().verify_password(password.as_bytes(), &hash)
+            .ok()
+            .and(user.cloned())
+    }
+
+    fn is_user_address(&self, address: &EmailAddress) -> bool {
+        *address != EmailAddress::Null && self.users.values()
+            .any(|(_, user)| user.owns(address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn backend() -> FileAuthBackend {
+        let salt = SaltString::from_b64("dGVzdHNhbHR0ZXN0c2FsdA").unwrap();
+        let hash = Argon2::default().hash_password(b"secret", &salt).unwrap();
+
+        let path = env::temp_dir().join(format!("ddelivery-credentials-{}", process::id()));
+        fs::write(&path, format!("# users\nalice:{hash}:alice@node1, Al@node1\n")).unwrap();
+        let backend = FileAuthBackend::load(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        backend
+    }
+
+    fn address(address: &str) -> EmailAddress {
+        EmailAddress::from_bytes(format!("<{address}>").into_bytes()).unwrap()
+    }
+
+    #[test]
+    fn users_are_authenticated_with_their_password() {
+        let backend = backend();
+
+        assert_eq!(backend.authenticate("alice", "secret").map(|it| it.name).as_deref(), Some("alice"));
+        assert!(backend.authenticate("alice", "wrong").is_none());
+        assert!(backend.authenticate("bob", "secret").is_none());
+        assert!(backend.authenticate("bob", "").is_none());
+    }
+
+    #[test]
+    fn user_addresses_are_known() {
+        let backend = backend();
+
+        assert!(backend.is_user_address(&address("alice@node1")));
+        assert!(backend.is_user_address(&address("al@NODE1")));
+        assert!(!backend.is_user_address(&address("bob@node1")));
+        assert!(!backend.is_user_address(&EmailAddress::Null));
+    }
+}
@@ -0,0 +1,52 @@
+use crate::{defaults::INBOX_AGENT_ID, domain::node_name, envelope::{Envelope, EnvelopeRecipient, NotifyConditions}, report::{Diagnostic, RecipientStatus, StatusNotification}};
+
+/// Recipient to which a message could not be delivered
+pub struct BouncedRecipient {
+    pub address: String,
+    /// Enhanced status code of the failure (RFC 3463)
+    pub status: String,
+    pub diagnostic: Diagnostic
+}
+
+/// Build a delivery status notification (RFC 3464) for the sender of a message, returning
+/// the inbox endpoint of the sender node and the bundle to send, `None` if it cannot be returned
+pub fn bounce(reporting_domain: &str, from: &str, recipients: &[BouncedRecipient], message: &[u8]) -> Option<(String, Vec<u8>)> {
+    let (_, domain) = from.rsplit_once('@')?;
+    let destination = format!("dtn://{}/{INBOX_AGENT_ID}", node_name(domain)?);
+
+    let content = StatusNotification {
+        reporting_domain,
+        to: from,
+        summary: ("Failure", "Your message could not be delivered to the following recipients."),
+        envid: None,
+        arrival: None,
+        recipients: recipients.iter()
+            .map(|recipient| RecipientStatus {
+                final_recipient: recipient.address.clone(),
+                original_recipient: None,
+                action: "failed",
+                status: recipient.status.clone(),
+                diagnostic: Some(recipient.diagnostic.clone())
+            })
+            .collect(),
+        message,
+        return_full: false
+    }.render();
+
+    // Notifications are sent from the null reverse path so that they are never bounced
+    let envelope = Envelope {
+        from: None,
+        envid: None,
+        return_full: false,
+        report_to: None,
+        recipients: vec![EnvelopeRecipient {
+            address: from.to_owned(),
+            notify: NotifyConditions::default(),
+            orcpt: None,
+            report_id: None
+        }],
+        body: content
+    };
+
+    Some((destination, envelope.to_bytes()))
+}
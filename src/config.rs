@@ -0,0 +1,64 @@
+use std::{collections::HashMap, env, fs, io, path::Path, str::FromStr};
+
+use thiserror::Error;
+
+pub const DEFAULT_CONFIG_FILE: &str = "/etc/ddelivery/receiver.conf";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read configuration file : {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid configuration line {0}")]
+    InvalidLine(usize),
+    #[error("Invalid value {1:?} for {0}")]
+    InvalidValue(String, String),
+    #[error("Missing configuration value {0}")]
+    Missing(String)
+}
+
+/// Settings read from a file with one `key = value` per line
+///
+/// Empty lines and lines starting with `#` are ignored. Each key can be
+/// overridden by the environment variable `DDELIVERY_<KEY>`.
+#[derive(Default)]
+pub struct Config {
+    values: HashMap<String, String>
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let mut values = HashMap::new();
+
+        for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line.split_once('=')
+                .ok_or(ConfigError::InvalidLine(i + 1))?;
+            values.insert(key.trim().to_lowercase(), value.trim().to_owned());
+        }
+
+        Ok(Self { values })
+    }
+
+    /// Value of a setting, from the environment first
+    pub fn get(&self, key: &str) -> Option<String> {
+        env::var(format!("DDELIVERY_{}", key.to_uppercase())).ok()
+            .or_else(|| self.values.get(key).cloned())
+    }
+
+    pub fn require(&self, key: &str) -> Result<String, ConfigError> {
+        self.get(key).ok_or_else(|| ConfigError::Missing(key.to_owned()))
+    }
+
+    /// Parsed value of a setting, `default` when it is not set
+    pub fn parse<T: FromStr>(&self, key: &str, default: T) -> Result<T, ConfigError> {
+        match self.get(key) {
+            Some(value) => value.parse()
+                .map_err(|_| ConfigError::InvalidValue(key.to_owned(), value)),
+            None => Ok(default)
+        }
+    }
+}
@@ -1,2 +1,7 @@
+use std::time::Duration;
+
 pub const OUTBOX_AGENT_ID:&str = "mail/outbox";
-pub const INBOX_AGENT_ID:&str = "mail/inbox";
\ No newline at end of file
+pub const INBOX_AGENT_ID:&str = "mail/inbox";
+
+/// Time after which the receiver gives up delivering a message
+pub const DEFAULT_MAX_DELIVERY_AGE: Duration = Duration::from_secs(5 * 24 * 60 * 60);
@@ -0,0 +1,289 @@
+use std::{path::PathBuf, time::Duration};
+
+use log::{debug, info, warn};
+use mail_send::{smtp::AssertReply, Credentials, SmtpClient, SmtpClientBuilder};
+use tokio::{io::{AsyncRead, AsyncWrite}, net::UnixStream, time::Instant};
+
+use crate::{config::{Config, ConfigError}, maildir::{Maildir, DEFAULT_MAILDIR_PATH}};
+
+pub const DEFAULT_LMTP_PORT: u16 = 24;
+pub const DEFAULT_SMTP_PORT: u16 = 25;
+
+/// Socket of the LMTP service of Dovecot
+pub const DEFAULT_LMTP_SOCKET: &str = "/run/dovecot/lmtp";
+
+/// Time without transaction after which the connection is checked with NOOP
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Time waited for each reply of the server
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Where received messages are delivered
+pub enum DeliveryTarget {
+    /// LMTP server listening on TCP
+    Lmtp { host: String, port: u16 },
+    /// LMTP server listening on a Unix socket
+    LmtpUnix { path: PathBuf },
+    /// SMTP relay, recipients being addressed with the domain of this node
+    Smtp {
+        host: String,
+        port: u16,
+        tls: RelayTls,
+        credentials: Option<(String, String)>
+    },
+    /// Maildir of each local user, written directly without mail server
+    Maildir { path: String }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelayTls {
+    None,
+    StartTls,
+    /// TLS from the start of the connection (RFC 8314)
+    Implicit
+}
+
+impl DeliveryTarget {
+    /// Read the target from the `delivery` setting and the settings of the selected backend
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        match config.get("delivery").as_deref() {
+            None | Some("lmtp") => Ok(DeliveryTarget::Lmtp {
+                host: config.get("lmtp_host").unwrap_or("localhost".to_owned()),
+                port: config.parse("lmtp_port", DEFAULT_LMTP_PORT)?
+            }),
+            Some("lmtp-unix") => Ok(DeliveryTarget::LmtpUnix {
+                path: config.get("lmtp_socket").unwrap_or(DEFAULT_LMTP_SOCKET.to_owned()).into()
+            }),
+            Some("smtp") => Ok(DeliveryTarget::Smtp {
+                host: config.require("smtp_host")?,
+                port: config.parse("smtp_port", DEFAULT_SMTP_PORT)?,
+                tls: match config.get("smtp_tls").as_deref() {
+                    None | Some("none") => RelayTls::None,
+                    Some("starttls") => RelayTls::StartTls,
+                    Some("tls") => RelayTls::Implicit,
+                    Some(value) => return Err(ConfigError::InvalidValue("smtp_tls".to_owned(), value.to_owned()))
+                },
+                credentials: match config.get("smtp_username") {
+                    Some(username) => Some((username, config.require("smtp_password")?)),
+                    None => None
+                }
+            }),
+            Some("maildir") => Ok(DeliveryTarget::Maildir {
+                path: config.get("maildir_path").unwrap_or(DEFAULT_MAILDIR_PATH.to_owned())
+            }),
+            Some(value) => Err(ConfigError::InvalidValue("delivery".to_owned(), value.to_owned()))
+        }
+    }
+}
+
+/// Backend delivering received messages to local users
+pub enum Delivery {
+    Server(Box<MailConnection>),
+    Maildir(Maildir)
+}
+
+impl Delivery {
+    pub fn new(target: DeliveryTarget, recipient_domain: &str) -> Self {
+        match target {
+            DeliveryTarget::Lmtp { host, port } => Delivery::Server(Box::new(MailConnection::new(
+                SmtpClientBuilder::new(host, port).lmtp(true), None, RelayTls::None, None
+            ))),
+            DeliveryTarget::LmtpUnix { path } => Delivery::Server(Box::new(MailConnection::new(
+                SmtpClientBuilder::new("localhost".to_owned(), 0).lmtp(true), Some(path), RelayTls::None, None
+            ))),
+            DeliveryTarget::Smtp { host, port, tls, credentials } => {
+                let mut builder = SmtpClientBuilder::new(host, port)
+                    .implicit_tls(tls == RelayTls::Implicit);
+                if let Some(credentials) = credentials {
+                    builder = builder.credentials(Credentials::from(credentials));
+                }
+                Delivery::Server(Box::new(MailConnection::new(builder, None, tls, Some(recipient_domain.to_owned()))))
+            },
+            DeliveryTarget::Maildir { path } => Delivery::Maildir(Maildir::new(path, recipient_domain))
+        }
+    }
+
+    /// When the idle server connection has to be checked, `None` if there is none
+    pub fn next_keepalive(&self) -> Option<Instant> {
+        match self {
+            Delivery::Server(connection) => connection.next_keepalive(),
+            Delivery::Maildir(_) => None
+        }
+    }
+
+    pub async fn keepalive(&mut self) {
+        if let Delivery::Server(connection) = self {
+            connection.keepalive().await;
+        }
+    }
+}
+
+/// Stream to the server, over TCP, TLS or a Unix socket
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Connection to the delivery server, established again whenever it is lost
+pub struct MailConnection {
+    builder: SmtpClientBuilder<String>,
+    socket: Option<PathBuf>,
+    tls: RelayTls,
+    /// Domain added to recipient user names, SMTP relays expecting complete addresses
+    recipient_domain: Option<String>,
+    client: Option<SmtpClient<Box<dyn Stream>>>,
+    last_activity: Instant
+}
+
+impl MailConnection {
+    fn new(builder: SmtpClientBuilder<String>, socket: Option<PathBuf>, tls: RelayTls, recipient_domain: Option<String>) -> Self {
+        Self {
+            builder: builder.timeout(COMMAND_TIMEOUT),
+            socket,
+            tls,
+            recipient_domain,
+            client: None,
+            last_activity: Instant::now()
+        }
+    }
+
+    fn protocol(&self) -> &'static str {
+        if self.builder.is_lmtp { "LMTP" } else { "SMTP" }
+    }
+
+    async fn connect(&self) -> Result<SmtpClient<Box<dyn Stream>>, mail_send::Error> {
+        match (&self.socket, self.tls) {
+            (Some(path), _) => {
+                let stream = tokio::time::timeout(self.builder.timeout, UnixStream::connect(path)).await
+                    .map_err(|_| mail_send::Error::Timeout)??;
+                let mut client = SmtpClient { stream: Box::new(stream) as Box<dyn Stream>, timeout: self.builder.timeout };
+                client.read().await?.assert_positive_completion()?;
+                client.capabilities(&self.builder.local_host, self.builder.is_lmtp).await?;
+                Ok(client)
+            },
+            (None, RelayTls::None) => {
+                let client = self.builder.connect_plain().await?;
+                Ok(SmtpClient { stream: Box::new(client.stream), timeout: client.timeout })
+            },
+            (None, _) => {
+                let client = self.builder.connect().await?;
+                Ok(SmtpClient { stream: Box::new(client.stream), timeout: client.timeout })
+            }
+        }
+    }
+
+    async fn client(&mut self) -> Result<&mut SmtpClient<Box<dyn Stream>>, mail_send::Error> {
+        if self.client.is_none() {
+            debug!("Connecting to {} server", self.protocol());
+            self.client = Some(self.connect().await?);
+            info!("Connected to {} server", self.protocol());
+        }
+
+        Ok(self.client.as_mut().unwrap())
+    }
+
+    /// Transmit a message to local users, giving the outcome for each recipient,
+    /// and sending it again on a new connection if the current one was lost
+    pub async fn send(&mut self, from: &str, users: &[&str], message: &[u8]) -> Result<Vec<Result<(), mail_send::Error>>, mail_send::Error> {
+        let recipients = users.iter()
+            .map(|user| match &self.recipient_domain {
+                Some(domain) => format!("{user}@{domain}"),
+                None => user.to_string()
+            })
+            .collect::<Vec<_>>();
+
+        let mut reconnected = self.client.is_none();
+
+        loop {
+            let is_lmtp = self.builder.is_lmtp;
+            let result = transaction(self.client().await?, is_lmtp, from, &recipients, message).await;
+            self.last_activity = Instant::now();
+
+            match result {
+                Ok(outcomes) => return Ok(outcomes),
+                Err(e) if is_connection_error(&e) => {
+                    self.client = None;
+                    if reconnected {
+                        return Err(e);
+                    }
+                    warn!("{} connection lost, reconnecting : {e}", self.protocol());
+                    reconnected = true;
+                },
+                Err(e) => {
+                    // Abort the failed transaction so that the next one starts cleanly
+                    if let Some(client) = self.client.as_mut() {
+                        if let Err(reset_error) = client.rset().await {
+                            warn!("Failed to reset {} transaction, closing connection : {reset_error}", self.protocol());
+                            self.client = None;
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// When the idle connection has to be checked, `None` while disconnected
+    pub fn next_keepalive(&self) -> Option<Instant> {
+        self.client.as_ref().map(|_| self.last_activity + KEEPALIVE_INTERVAL)
+    }
+
+    /// Check the idle connection, closing it if the server does not answer
+    pub async fn keepalive(&mut self) {
+        let Some(client) = self.client.as_mut() else {
+            return;
+        };
+
+        self.last_activity = Instant::now();
+
+        if let Err(e) = client.noop().await {
+            info!("{} connection closed after inactivity : {e}", self.protocol());
+            self.client = None;
+        }
+    }
+}
+
+/// Run a mail transaction, LMTP replying after the message for each accepted recipient (RFC 2033 section 4.2)
+/// where SMTP gives a single reply for all of them
+async fn transaction(client: &mut SmtpClient<Box<dyn Stream>>, is_lmtp: bool, from: &str, recipients: &[String], message: &[u8]) -> Result<Vec<Result<(), mail_send::Error>>, mail_send::Error> {
+    client.cmd(format!("MAIL FROM:<{from}>\r\n")).await?.assert_positive_completion()?;
+
+    let mut outcomes = Vec::with_capacity(recipients.len());
+    for recipient in recipients {
+        outcomes.push(client.cmd(format!("RCPT TO:<{recipient}>\r\n")).await?.assert_positive_completion());
+    }
+
+    let accepted = outcomes.iter().filter(|it| it.is_ok()).count();
+    if accepted == 0 {
+        client.rset().await?;
+        return Ok(outcomes);
+    }
+
+    client.cmd(b"DATA\r\n").await?.assert_code(354)?;
+
+    let replies = tokio::time::timeout(COMMAND_TIMEOUT, async {
+        client.write_message(message).await?;
+        match is_lmtp {
+            true => client.read_many(accepted).await,
+            false => client.read().await.map(|reply| vec![reply; accepted])
+        }
+    }).await.map_err(|_| mail_send::Error::Timeout)??;
+
+    let mut replies = replies.into_iter();
+    for outcome in outcomes.iter_mut().filter(|it| it.is_ok()) {
+        *outcome = replies.next()
+            .ok_or(mail_send::Error::UnparseableReply)?
+            .assert_positive_completion();
+    }
+
+    Ok(outcomes)
+}
+
+/// Whether an error means the connection can no longer be used
+fn is_connection_error(error: &mail_send::Error) -> bool {
+    match error {
+        mail_send::Error::Io(_) | mail_send::Error::Timeout | mail_send::Error::UnparseableReply => true,
+        // Service not available, the server is closing the connection
+        mail_send::Error::UnexpectedReply(response) => response.code == 421,
+        _ => false
+    }
+}
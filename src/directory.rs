@@ -0,0 +1,137 @@
+use std::{collections::HashMap, fmt::Display, fs, io, path::Path};
+
+use thiserror::Error;
+
+/// Mailbox known by a local directory
+#[derive(Debug, Clone)]
+pub struct Mailbox {
+    pub name: Option<String>,
+    pub address: String
+}
+
+impl Mailbox {
+    /// Parse `Full Name <address>` or a bare address
+    fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+
+        match value.split_once('<') {
+            Some((name, address)) => {
+                let address = address.strip_suffix('>')?.trim();
+                let name = name.trim();
+                (!address.is_empty()).then(|| Self {
+                    name: (!name.is_empty()).then(|| name.to_owned()),
+                    address: address.to_owned()
+                })
+            },
+            None => (!value.is_empty()).then(|| Self { name: None, address: value.to_owned() })
+        }
+    }
+
+    fn local_part(&self) -> &str {
+        self.address.rsplit_once('@')
+            .map(|(local_part, _)| local_part)
+            .unwrap_or(&self.address)
+    }
+}
+
+impl Display for Mailbox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{name} <{}>", self.address),
+            None => write!(f, "<{}>", self.address)
+        }
+    }
+}
+
+pub trait Directory: Send + Sync {
+    /// Find users matching a VRFY query by user name, address or local part
+    fn verify(&self, query: &str) -> Vec<Mailbox>;
+
+    /// Members of a mailing list, `None` if there is no such list
+    fn expand(&self, list: &str) -> Option<Vec<Mailbox>>;
+}
+
+#[derive(Debug, Error)]
+pub enum DirectoryFileError {
+    #[error("Failed to read directory file : {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid directory line {0}")]
+    InvalidLine(usize)
+}
+
+/// Directory stored in a local file with one entry per line :
+/// `user:name:Full Name <address>` or `list:name:address1,address2`
+///
+/// Empty lines and lines starting with `#` are ignored.
+pub struct FileDirectory {
+    users: Vec<(String, Mailbox)>,
+    lists: HashMap<String, Vec<Mailbox>>
+}
+
+impl FileDirectory {
+    pub fn load(path: &Path) -> Result<Self, DirectoryFileError> {
+        let mut users = Vec::new();
+        let mut lists = HashMap::new();
+
+        for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.splitn(3, ':');
+            let (Some(kind), Some(name), Some(value)) = (fields.next(), fields.next(), fields.next()) else {
+                return Err(DirectoryFileError::InvalidLine(i+1));
+            };
+
+            match kind {
+                "user" => {
+                    let mailbox = Mailbox::parse(value)
+                        .ok_or(DirectoryFileError::InvalidLine(i+1))?;
+                    users.push((name.to_owned(), mailbox));
+                },
+                "list" => {
+                    let members = value.split(',')
+                        .filter(|it| !it.trim().is_empty())
+                        .map(Mailbox::parse)
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or(DirectoryFileError::InvalidLine(i+1))?;
+                    lists.insert(name.to_ascii_lowercase(), members);
+                },
+                _ => return Err(DirectoryFileError::InvalidLine(i+1))
+            }
+        }
+
+        Ok(Self { users, lists })
+    }
+}
+
+/// Remove the angle brackets of a path argument
+fn strip_path(query: &str) -> &str {
+    let query = query.trim();
+    query.strip_prefix('<')
+        .and_then(|it| it.strip_suffix('>'))
+        .unwrap_or(query)
+}
+
+impl Directory for FileDirectory {
+    fn verify(&self, query: &str) -> Vec<Mailbox> {
+        let query = strip_path(query);
+
+        self.users.iter()
+            .filter(|(name, mailbox)| name.eq_ignore_ascii_case(query)
+                || mailbox.address.eq_ignore_ascii_case(query)
+                || mailbox.local_part().eq_ignore_ascii_case(query))
+            .map(|(_, mailbox)| mailbox.clone())
+            .collect()
+    }
+
+    fn expand(&self, list: &str) -> Option<Vec<Mailbox>> {
+        let list = strip_path(list);
+        let name = list.rsplit_once('@')
+            .map(|(local_part, _)| local_part)
+            .unwrap_or(list);
+
+        self.lists.get(&name.to_ascii_lowercase()).cloned()
+    }
+}
@@ -0,0 +1,23 @@
+/// Normalize a mail domain to the ASCII form used as DTN node name
+///
+/// Internationalized domains are converted to their punycode (A-label) form so that
+/// a domain written in Unicode or punycode designates the same node.
+pub fn node_name(domain: &str) -> Option<String> {
+    idna::domain_to_ascii(domain).ok()
+        .filter(|it| !it.is_empty())
+}
+
+/// Check a local part as dot-atom or quoted string, allowing UTF-8 characters (RFC 6531 section 3.3)
+pub fn is_valid_local_part(local_part: &str) -> bool {
+    if local_part.is_empty() || local_part.len() > 64 {
+        return false;
+    }
+
+    if local_part.len() >= 2 && local_part.starts_with('"') && local_part.ends_with('"') {
+        return local_part[1..local_part.len()-1].chars()
+            .all(|it| it != '\r' && it != '\n');
+    }
+
+    local_part.split('.').all(|atom| !atom.is_empty() && atom.chars()
+        .all(|it| !it.is_ascii() || it.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(it)))
+}
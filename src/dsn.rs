@@ -0,0 +1,532 @@
+use std::{collections::HashMap, sync::{mpsc::Sender, Arc, Mutex}, thread, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use log::{debug, error, info, warn};
+use ud3tn_aap::Agent;
+
+use crate::{defaults::DEFAULT_MAX_DELIVERY_AGE, envelope::{Envelope, EnvelopeRecipient, NotifyConditions}, mail_sender::{submit_mail, SenderMsg}, report::{self, Diagnostic, DiagnosticType, RecipientStatus, StatusNotification}, smtp::{DsnNotify, DsnReturn, EmailAddress, Mail, MailParameters, OriginalRecipient, RcptParameters, Recipient}, spool::{Spool, SpoolError}};
+
+pub const REPORT_AGENT_ID:&str = "mail/report";
+
+pub const DEFAULT_DELAY_WARNING: Duration = Duration::from_secs(4 * 60 * 60);
+/// Lifetime given by archipel-core to bundles, after which they are dropped in transit
+const BUNDLE_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Long enough for the bundle to reach its node, the receiver to give up its delivery and
+/// the report to come back, so that a mail still being delivered is never reported failed
+pub const DEFAULT_EXPIRY: Duration = Duration::from_secs(DEFAULT_MAX_DELIVERY_AGE.as_secs() + 2 * BUNDLE_LIFETIME.as_secs());
+
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct DsnConfig {
+    /// Time after which a delayed delivery is notified
+    pub delay_warning: Duration,
+    /// Time after which a delivery without report is considered failed
+    pub expiry: Duration
+}
+
+/// Outcome of a delivery reported to the sender
+#[derive(Debug, Clone, Copy)]
+enum Action {
+    Delivered,
+    Delayed,
+    Failed(&'static str)
+}
+
+/// Recipient waiting for the delivery report of its bundle
+struct PendingReport {
+    from: EmailAddress,
+    envid: Option<String>,
+    ret: DsnReturn,
+    recipient: Recipient,
+    notify: DsnNotify,
+    /// Message returned in failure notifications, only its header when RET=HDRS
+    /// or when no failure is notified
+    content: Vec<u8>,
+    arrival: SystemTime,
+    delay_notified: bool,
+    /// Identifier in the report spool, `None` if it could not be stored
+    spool_id: Option<String>
+}
+
+impl PendingReport {
+    fn new(mail: &Mail, recipient: &Recipient, notify: DsnNotify, arrival: SystemTime) -> Self {
+        let ret = mail.parameters.ret.unwrap_or(DsnReturn::Headers);
+        let content = match ret {
+            DsnReturn::Full if notify.failure => &mail.content[..],
+            _ => report::headers(&mail.content)
+        };
+
+        Self {
+            from: mail.from.clone(),
+            envid: mail.parameters.envid.clone(),
+            ret,
+            recipient: recipient.clone(),
+            notify,
+            content: content.to_vec(),
+            arrival,
+            delay_notified: false,
+            spool_id: None
+        }
+    }
+
+    /// Envelope recording the report awaited for the recipient, with the delay
+    /// condition cleared once notified
+    fn spool_envelope(&self, id: &str) -> Envelope {
+        Envelope {
+            from: Some(self.from.address().to_owned()),
+            envid: self.envid.clone(),
+            return_full: self.ret == DsnReturn::Full,
+            report_to: None,
+            recipients: vec![EnvelopeRecipient {
+                address: self.recipient.address.address().to_owned(),
+                notify: NotifyConditions {
+                    success: self.notify.success,
+                    failure: self.notify.failure,
+                    delay: self.notify.delay && !self.delay_notified
+                },
+                orcpt: self.recipient.parameters.orcpt.as_ref()
+                    .map(|it| (it.address_type.clone(), it.address.clone())),
+                report_id: Some(id.to_owned())
+            }],
+            body: Vec::new()
+        }
+    }
+
+    /// Awaited report read back from its spooled envelope, with its identifier
+    fn from_spool(spool_id: String, stored: SystemTime, envelope: Envelope) -> Option<(String, Self)> {
+        let parse_address = |address: &str| EmailAddress::from_bytes(format!("<{address}>").into_bytes()).ok();
+
+        let [recipient] = &envelope.recipients[..] else {
+            return None;
+        };
+
+        let notify = DsnNotify {
+            success: recipient.notify.success,
+            failure: recipient.notify.failure,
+            delay: recipient.notify.delay
+        };
+
+        Some((recipient.report_id.clone()?, Self {
+            from: parse_address(envelope.from.as_deref()?)?,
+            envid: envelope.envid.clone(),
+            ret: if envelope.return_full { DsnReturn::Full } else { DsnReturn::Headers },
+            recipient: Recipient {
+                address: parse_address(&recipient.address)?,
+                parameters: RcptParameters {
+                    notify: Some(notify),
+                    orcpt: recipient.orcpt.clone()
+                        .map(|(address_type, address)| OriginalRecipient { address_type, address })
+                }
+            },
+            notify,
+            content: envelope.body,
+            arrival: stored,
+            delay_notified: false,
+            spool_id: Some(spool_id)
+        }))
+    }
+}
+
+/// Keep track of sent bundles requesting delivery status notifications
+pub struct ReportTracker {
+    config: DsnConfig,
+    node_name: String,
+    report_to: String,
+    id_prefix: u64,
+    state: Mutex<(u64, HashMap<String, PendingReport>)>,
+    /// Reports still awaited, kept across restarts like the mails themselves
+    spool: Spool
+}
+
+impl ReportTracker {
+    /// Create the tracker, awaiting again the reports left in its spool by a previous run
+    pub fn new(config: DsnConfig, node_eid: &str, spool: Spool) -> Result<Self, SpoolError> {
+        let mut pending = HashMap::new();
+        for (spool_id, stored, envelope) in spool.load_all()? {
+            match PendingReport::from_spool(spool_id.clone(), stored, envelope) {
+                Some((id, report)) => {
+                    pending.insert(id, report);
+                },
+                None => {
+                    error!("Invalid spooled delivery report {spool_id}, moving it to dead letters");
+                    if let Err(e) = spool.dead_letter(&spool_id) {
+                        error!("Failed to move delivery report {spool_id} to dead letters : {e}");
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            config,
+            node_name: node_eid[6..node_eid.len()-1].to_owned(),
+            report_to: format!("{node_eid}{REPORT_AGENT_ID}"),
+            id_prefix: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            state: Mutex::new((0, pending)),
+            spool
+        })
+    }
+
+    /// Endpoint receiving delivery reports for this node
+    pub fn report_to(&self) -> &str {
+        &self.report_to
+    }
+
+    /// Register a recipient of a mail, returning the identifier of the awaited
+    /// delivery report if notifications are requested
+    pub fn register(&self, mail: &Mail, recipient: &Recipient) -> Option<String> {
+        // Notifications are never sent for mails without reverse path
+        if mail.from == EmailAddress::Null {
+            return None;
+        }
+
+        let notify = notify_conditions(&recipient.parameters);
+        if notify == DsnNotify::default() {
+            return None;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.0 += 1;
+        let id = format!("{}.{}@{}", self.id_prefix, state.0, self.node_name);
+
+        let mut report = PendingReport::new(mail, recipient, notify, SystemTime::now());
+        match self.spool.store(&report.content, &report.spool_envelope(&id)) {
+            Ok(spool_id) => report.spool_id = Some(spool_id),
+            Err(e) => error!("Failed to store delivery report {id}, it will be lost on restart : {e}")
+        }
+        state.1.insert(id.clone(), report);
+
+        Some(id)
+    }
+
+    /// Failure notification for a recipient whose bundle could not be sent,
+    /// if its sender requested one
+    pub fn unsent(&self, mail: &Mail, recipient: &Recipient, arrival: SystemTime, diagnostic: &str) -> Option<Mail> {
+        let notify = notify_conditions(&recipient.parameters);
+        if mail.from == EmailAddress::Null || !notify.failure {
+            return None;
+        }
+
+        let report = PendingReport::new(mail, recipient, notify, arrival);
+        Some(self.notification(&report, Action::Failed("5.4.0"), Some(local_diagnostic(diagnostic))))
+    }
+
+    /// Forget a registered recipient whose bundle could not be sent
+    pub fn cancel(&self, id: &str) {
+        self.resolve(id);
+    }
+
+    fn resolve(&self, id: &str) -> Option<PendingReport> {
+        let report = self.state.lock().unwrap().1.remove(id)?;
+        self.unspool(&report);
+        Some(report)
+    }
+
+    fn unspool(&self, report: &PendingReport) {
+        if let Some(spool_id) = &report.spool_id {
+            if let Err(e) = self.spool.remove(spool_id) {
+                error!("Failed to remove delivery report {spool_id} from spool : {e}");
+            }
+        }
+    }
+
+    /// Resolve a delivery report received from `source`, returning the notification
+    /// to send if the sender requested one for this outcome
+    ///
+    /// Reports are of the form `<id> delivered` or `<id> failed <diagnostic>`, and are
+    /// sent for every recipient with a report identifier whatever its NOTIFY conditions.
+    /// The diagnostic is given with its type, `smtp; <reply>`, when it comes from the receiving server.
+    fn handle_report(&self, source: &str, report: &str) -> Option<Mail> {
+        let mut fields = report.trim_end().splitn(3, ' ');
+
+        let (id, action, diagnostic) = match (fields.next(), fields.next()) {
+            (Some(id), Some("delivered")) => (id, Action::Delivered, None),
+            (Some(id), Some("failed")) => (id, Action::Failed("5.0.0"), fields.next().map(parse_diagnostic)),
+            _ => {
+                warn!("Invalid delivery report received from endpoint {source}");
+                return None;
+            }
+        };
+
+        let Some(pending) = self.resolve(id) else {
+            debug!("Delivery report {id} received from endpoint {source} is not awaited");
+            return None;
+        };
+
+        info!("Mail to {} {action:?} according to endpoint {source}", pending.recipient.address);
+
+        let requested = match action {
+            Action::Delivered => pending.notify.success,
+            _ => pending.notify.failure
+        };
+
+        requested.then(|| self.notification(&pending, action, diagnostic))
+    }
+
+    /// Collect notifications of deliveries delayed or expired at this time
+    fn expired(&self) -> Vec<Mail> {
+        let now = SystemTime::now();
+        let mut state = self.state.lock().unwrap();
+        let mut notifications = Vec::new();
+
+        state.1.retain(|id, report| {
+            let elapsed = now.duration_since(report.arrival).unwrap_or_default();
+
+            if elapsed >= self.config.expiry {
+                if report.notify.failure {
+                    notifications.push(self.notification(report, Action::Failed("5.4.7"), Some(local_diagnostic("Delivery time expired"))));
+                }
+                self.unspool(report);
+                return false;
+            }
+
+            if elapsed >= self.config.delay_warning && report.notify.delay && !report.delay_notified {
+                notifications.push(self.notification(report, Action::Delayed, None));
+                report.delay_notified = true;
+
+                if let Some(spool_id) = &report.spool_id {
+                    if let Err(e) = self.spool.update(spool_id, &report.spool_envelope(id)) {
+                        error!("Failed to update delivery report {spool_id} : {e}");
+                    }
+                }
+            }
+
+            true
+        });
+
+        notifications
+    }
+
+    /// Build a multipart/report delivery status notification (RFC 3464) for the sender of a mail
+    fn notification(&self, report: &PendingReport, action: Action, diagnostic: Option<Diagnostic>) -> Mail {
+        let (action_name, status, summary) = match action {
+            Action::Delivered => ("delivered", "2.0.0", ("Delivered",
+                "Your message was delivered to the following recipient.")),
+            Action::Delayed => ("delayed", "4.4.7", ("Delayed",
+                "Your message has not been delivered yet to the following recipient. Delivery will continue to be attempted.")),
+            Action::Failed(status) => ("failed", status, ("Failure",
+                "Your message could not be delivered to the following recipient."))
+        };
+
+        let content = StatusNotification {
+            reporting_domain: &self.node_name,
+            to: report.from.address(),
+            summary,
+            envid: report.envid.as_deref(),
+            arrival: Some(report.arrival),
+            recipients: vec![RecipientStatus {
+                final_recipient: report.recipient.address.address().to_owned(),
+                original_recipient: report.recipient.parameters.orcpt.as_ref()
+                    .map(|it| (it.address_type.clone(), it.address.clone())),
+                action: action_name,
+                status: status.to_owned(),
+                diagnostic
+            }],
+            message: &report.content,
+            return_full: matches!(action, Action::Failed(_)) && report.ret == DsnReturn::Full
+        }.render();
+
+        let mut mail = Mail::new(EmailAddress::Null, MailParameters::default());
+        mail.receipients.push(Recipient {
+            address: report.from.clone(),
+            parameters: RcptParameters { notify: Some(DsnNotify::default()), orcpt: None }
+        });
+        mail.content = content;
+        mail
+    }
+}
+
+/// Diagnostic of a failure found by this node
+fn local_diagnostic(text: &str) -> Diagnostic {
+    Diagnostic { code_type: DiagnosticType::DDelivery, text: text.to_owned() }
+}
+
+/// Diagnostic of a delivery report, typed as `smtp; <reply>` when it is a reply of the
+/// receiving server and unknown or untyped ones being errors of the receiver
+fn parse_diagnostic(value: &str) -> Diagnostic {
+    match value.split_once("; ") {
+        Some((code_type, text)) if code_type.eq_ignore_ascii_case("smtp") =>
+            Diagnostic { code_type: DiagnosticType::Smtp, text: text.to_owned() },
+        Some((code_type, text)) if code_type.eq_ignore_ascii_case("X-DDelivery") => local_diagnostic(text),
+        _ => local_diagnostic(value)
+    }
+}
+
+/// Conditions requiring a notification for a recipient, FAILURE when NOTIFY is not given
+pub fn notify_conditions(parameters: &RcptParameters) -> DsnNotify {
+    parameters.notify
+        .unwrap_or(DsnNotify { success: false, failure: true, delay: false })
+}
+
+/// Receive delivery reports from destination nodes and notify senders
+pub fn run_report_task(mut report_agent: Agent, tracker: Arc<ReportTracker>, sender_channel: Sender<SenderMsg>, spool: Arc<Spool>) {
+    debug!("Starting delivery report task");
+
+    loop {
+        let (source, bundle) = match report_agent.recv_bundle() {
+            Ok(b) => b,
+            Err(e) => {
+                error!("Failed to receive delivery report from DTN: {e}");
+                continue;
+            }
+        };
+
+        if let Some(notification) = tracker.handle_report(&source, &String::from_utf8_lossy(&bundle)) {
+            if let Err(e) = submit_mail(&sender_channel, &spool, notification, None) {
+                error!("Failed to send delivery status notification : {e}");
+            }
+        }
+    }
+}
+
+/// Periodically notify senders of delayed or expired deliveries
+pub fn run_expiry_task(tracker: Arc<ReportTracker>, sender_channel: Sender<SenderMsg>, spool: Arc<Spool>) {
+    loop {
+        thread::sleep(EXPIRY_CHECK_INTERVAL);
+
+        for notification in tracker.expired() {
+            if let Err(e) = submit_mail(&sender_channel, &spool, notification, None) {
+                error!("Failed to send delivery status notification : {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT: &[u8] = b"Subject: Test\r\n\r\nHello\r\n";
+
+    fn spool(name: &str) -> Spool {
+        let directory = std::env::temp_dir().join(format!("ddelivery-reports-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        Spool::open(directory.join("reports"), directory.join("dead-letter")).unwrap()
+    }
+
+    fn tracker_with(spool: Spool, expiry: Duration) -> ReportTracker {
+        ReportTracker::new(DsnConfig { delay_warning: Duration::ZERO, expiry }, "dtn://node1/", spool).unwrap()
+    }
+
+    fn tracker() -> ReportTracker {
+        tracker_with(spool(&format!("{:?}", std::thread::current().id())), Duration::ZERO)
+    }
+
+    fn sent_mail(parameters: MailParameters, notify: Option<DsnNotify>) -> (Mail, Recipient) {
+        let mut mail = Mail::new(EmailAddress::from_bytes(b"<alice@node1>".to_vec()).unwrap(), parameters);
+        let recipient = Recipient {
+            address: EmailAddress::from_bytes(b"<bob@node2>".to_vec()).unwrap(),
+            parameters: RcptParameters { notify, orcpt: None }
+        };
+        mail.receipients.push(recipient.clone());
+        mail.content = CONTENT.to_vec();
+        (mail, recipient)
+    }
+
+    #[test]
+    fn delivered_recipient_with_default_notify_is_not_notified() {
+        let tracker = tracker();
+        let (mail, recipient) = sent_mail(MailParameters::default(), None);
+
+        let id = tracker.register(&mail, &recipient).expect("failures are notified by default");
+
+        assert!(tracker.handle_report("dtn://node2/mail/receipt", &format!("{id} delivered")).is_none());
+        assert!(tracker.expired().is_empty());
+    }
+
+    #[test]
+    fn failed_recipient_with_default_notify_is_notified() {
+        let tracker = tracker();
+        let (mail, recipient) = sent_mail(MailParameters::default(), None);
+
+        let id = tracker.register(&mail, &recipient).unwrap();
+        let notification = tracker.handle_report("dtn://node2/mail/receipt", &format!("{id} failed No such user"))
+            .expect("failure is notified");
+
+        assert_eq!(notification.from, EmailAddress::Null);
+        assert!(String::from_utf8_lossy(&notification.content).contains("Diagnostic-Code: X-DDelivery; No such user"));
+
+        let id = tracker.register(&mail, &recipient).unwrap();
+        let notification = tracker.handle_report("dtn://node2/mail/receipt", &format!("{id} failed smtp; 550 5.1.1 No such user"))
+            .expect("failure is notified");
+        assert!(String::from_utf8_lossy(&notification.content).contains("Diagnostic-Code: smtp; 550 5.1.1 No such user"));
+    }
+
+    #[test]
+    fn never_notified_recipient_is_not_registered() {
+        let (mail, recipient) = sent_mail(MailParameters::default(), Some(DsnNotify::default()));
+
+        assert!(tracker().register(&mail, &recipient).is_none());
+    }
+
+    #[test]
+    fn unresolved_recipient_expires() {
+        let tracker = tracker();
+        let (mail, recipient) = sent_mail(MailParameters::default(), None);
+
+        tracker.register(&mail, &recipient).unwrap();
+
+        assert_eq!(tracker.expired().len(), 1);
+        assert!(tracker.expired().is_empty());
+    }
+
+    #[test]
+    fn only_headers_are_kept_without_full_return() {
+        let tracker = tracker();
+
+        let (mail, recipient) = sent_mail(MailParameters::default(), None);
+        let id = tracker.register(&mail, &recipient).unwrap();
+        assert_eq!(tracker.resolve(&id).unwrap().content, b"Subject: Test\r\n");
+
+        let (mail, recipient) = sent_mail(MailParameters { ret: Some(DsnReturn::Full), ..Default::default() },
+            Some(DsnNotify { success: true, failure: false, delay: false }));
+        let id = tracker.register(&mail, &recipient).unwrap();
+        assert_eq!(tracker.resolve(&id).unwrap().content, b"Subject: Test\r\n");
+
+        let (mail, recipient) = sent_mail(MailParameters { ret: Some(DsnReturn::Full), ..Default::default() }, None);
+        let id = tracker.register(&mail, &recipient).unwrap();
+        assert_eq!(tracker.resolve(&id).unwrap().content, CONTENT);
+    }
+
+    #[test]
+    fn unsent_recipient_is_notified_if_requested() {
+        let tracker = tracker();
+
+        let (mail, recipient) = sent_mail(MailParameters::default(), None);
+        let notification = tracker.unsent(&mail, &recipient, SystemTime::now(), "Bundle refused")
+            .expect("failure is notified");
+        let content = String::from_utf8_lossy(&notification.content);
+        assert!(content.contains("Status: 5.4.0"));
+        assert!(content.contains("Diagnostic-Code: X-DDelivery; Bundle refused"));
+
+        let (mail, recipient) = sent_mail(MailParameters::default(), Some(DsnNotify::default()));
+        assert!(tracker.unsent(&mail, &recipient, SystemTime::now(), "Bundle refused").is_none());
+    }
+
+    #[test]
+    fn awaited_reports_survive_a_restart() {
+        let spool_directory = std::env::temp_dir().join(format!("ddelivery-reports-restart-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&spool_directory);
+        let open = || Spool::open(spool_directory.join("reports"), spool_directory.join("dead-letter")).unwrap();
+
+        let (mail, recipient) = sent_mail(MailParameters { envid: Some("QQ314159".to_owned()), ..Default::default() },
+            Some(DsnNotify { success: true, failure: true, delay: true }));
+        let id = tracker_with(open(), Duration::MAX).register(&mail, &recipient).unwrap();
+
+        let tracker = tracker_with(open(), Duration::MAX);
+        assert_eq!(tracker.expired().len(), 1, "delay is notified");
+
+        // The delay notification is not sent again after another restart
+        let tracker = tracker_with(open(), Duration::MAX);
+        assert!(tracker.expired().is_empty());
+
+        let notification = tracker.handle_report("dtn://node2/mail/receipt", &format!("{id} delivered"))
+            .expect("delivery is notified");
+        let content = String::from_utf8_lossy(&notification.content);
+        assert!(content.contains("Original-Envelope-Id: QQ314159"));
+        assert!(content.contains("Final-Recipient: rfc822; bob@node2"));
+
+        assert!(tracker_with(open(), Duration::MAX).handle_report("dtn://node2/mail/receipt", &format!("{id} delivered")).is_none());
+
+        std::fs::remove_dir_all(spool_directory).unwrap();
+    }
+}
@@ -0,0 +1,422 @@
+use thiserror::Error;
+
+/// Version of the envelope format produced by this node
+pub const ENVELOPE_VERSION: u64 = 1;
+
+/// CBOR self-described tag (RFC 8949 section 3.4.6) starting every envelope,
+/// which distinguishes envelopes from legacy raw RFC 5322 payloads
+const ENVELOPE_MAGIC: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+/// SMTP envelope and message carried in a mail bundle, encoded in CBOR as
+/// `[version, from, envid, return_full, report_to, [recipient...], body]`
+/// with each recipient as `[address, notify, orcpt, report_id]`
+///
+/// Items appended by later revisions of a version are ignored when decoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    /// Reverse path, `None` for the null sender
+    pub from: Option<String>,
+    /// Envelope identifier given by the client (RFC 3461 ENVID)
+    pub envid: Option<String>,
+    /// Whether failure notifications return the full message (RFC 3461 RET)
+    pub return_full: bool,
+    /// Endpoint awaiting delivery reports of recipients with a report identifier
+    pub report_to: Option<String>,
+    pub recipients: Vec<EnvelopeRecipient>,
+    pub body: Vec<u8>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvelopeRecipient {
+    pub address: String,
+    /// Delivery outcomes to report (RFC 3461 NOTIFY)
+    pub notify: NotifyConditions,
+    /// Original recipient as address type and address (RFC 3461 ORCPT)
+    pub orcpt: Option<(String, String)>,
+    /// Identifier of the delivery report awaited by the sender
+    pub report_id: Option<String>
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NotifyConditions {
+    pub success: bool,
+    pub failure: bool,
+    pub delay: bool
+}
+
+impl NotifyConditions {
+    fn to_flags(self) -> u64 {
+        (self.success as u64) | (self.failure as u64) << 1 | (self.delay as u64) << 2
+    }
+
+    fn from_flags(flags: u64) -> Self {
+        Self { success: flags & 1 != 0, failure: flags & 2 != 0, delay: flags & 4 != 0 }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum EnvelopeError {
+    #[error("Payload is not a mail envelope")]
+    NotEnvelope,
+    #[error("Unsupported envelope version {0}")]
+    UnsupportedVersion(u64),
+    #[error("Invalid envelope encoding")]
+    Invalid
+}
+
+impl Envelope {
+    /// Encode the envelope as a bundle payload
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = ENVELOPE_MAGIC.to_vec();
+
+        write_head(&mut out, MAJOR_ARRAY, 7);
+        write_head(&mut out, MAJOR_UNSIGNED, ENVELOPE_VERSION);
+        write_optional_text(&mut out, self.from.as_deref());
+        write_optional_text(&mut out, self.envid.as_deref());
+        out.push(if self.return_full { SIMPLE_TRUE } else { SIMPLE_FALSE });
+        write_optional_text(&mut out, self.report_to.as_deref());
+
+        write_head(&mut out, MAJOR_ARRAY, self.recipients.len() as u64);
+        for recipient in self.recipients.iter() {
+            write_head(&mut out, MAJOR_ARRAY, 4);
+            write_text(&mut out, &recipient.address);
+            write_head(&mut out, MAJOR_UNSIGNED, recipient.notify.to_flags());
+            match &recipient.orcpt {
+                Some((address_type, address)) => {
+                    write_head(&mut out, MAJOR_ARRAY, 2);
+                    write_text(&mut out, address_type);
+                    write_text(&mut out, address);
+                },
+                None => out.push(SIMPLE_NULL)
+            }
+            write_optional_text(&mut out, recipient.report_id.as_deref());
+        }
+
+        write_head(&mut out, MAJOR_BYTES, self.body.len() as u64);
+        out.extend_from_slice(&self.body);
+
+        out
+    }
+
+    /// Decode a bundle payload, failing with [EnvelopeError::NotEnvelope] for legacy raw messages
+    pub fn from_bytes(payload: &[u8]) -> Result<Self, EnvelopeError> {
+        let Some(payload) = payload.strip_prefix(&ENVELOPE_MAGIC) else {
+            return Err(EnvelopeError::NotEnvelope);
+        };
+
+        let mut reader = Reader { data: payload, position: 0 };
+
+        let items = reader.expect(MAJOR_ARRAY)?;
+        let version = reader.expect(MAJOR_UNSIGNED)?;
+        if version != ENVELOPE_VERSION {
+            return Err(EnvelopeError::UnsupportedVersion(version));
+        }
+        if items < 7 {
+            return Err(EnvelopeError::Invalid);
+        }
+
+        let from = reader.optional_text()?;
+        let envid = reader.optional_text()?;
+        let return_full = reader.bool()?;
+        let report_to = reader.optional_text()?;
+
+        let recipient_count = reader.expect(MAJOR_ARRAY)?;
+        let mut recipients = Vec::new();
+        for _ in 0..recipient_count {
+            let items = reader.expect(MAJOR_ARRAY)?;
+            if items < 4 {
+                return Err(EnvelopeError::Invalid);
+            }
+
+            let address = reader.text()?;
+            let notify = NotifyConditions::from_flags(reader.expect(MAJOR_UNSIGNED)?);
+            let orcpt = if reader.null()? {
+                None
+            } else {
+                if reader.expect(MAJOR_ARRAY)? != 2 {
+                    return Err(EnvelopeError::Invalid);
+                }
+                Some((reader.text()?, reader.text()?))
+            };
+            let report_id = reader.optional_text()?;
+
+            for _ in 4..items {
+                reader.skip()?;
+            }
+
+            recipients.push(EnvelopeRecipient { address, notify, orcpt, report_id });
+        }
+
+        let body = reader.bytes()?;
+
+        for _ in 7..items {
+            reader.skip()?;
+        }
+
+        Ok(Self { from, envid, return_full, report_to, recipients, body })
+    }
+}
+
+const MAJOR_UNSIGNED: u8 = 0;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+const MAJOR_TAG: u8 = 6;
+
+const SIMPLE_FALSE: u8 = 0xf4;
+const SIMPLE_TRUE: u8 = 0xf5;
+const SIMPLE_NULL: u8 = 0xf6;
+
+/// Nesting accepted in skipped items, bounding the recursion on untrusted payloads
+const MAX_SKIP_DEPTH: usize = 16;
+
+/// Write a CBOR data item head with its argument in the shortest form
+fn write_head(out: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    match value {
+        0..=23 => out.push(major | value as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, value as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend_from_slice(&(value as u16).to_be_bytes());
+        },
+        0x10000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend_from_slice(&(value as u32).to_be_bytes());
+        },
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+}
+
+fn write_text(out: &mut Vec<u8>, text: &str) {
+    write_head(out, MAJOR_TEXT, text.len() as u64);
+    out.extend_from_slice(text.as_bytes());
+}
+
+fn write_optional_text(out: &mut Vec<u8>, text: Option<&str>) {
+    match text {
+        Some(text) => write_text(out, text),
+        None => out.push(SIMPLE_NULL)
+    }
+}
+
+/// Reader of the definite length CBOR items used by envelopes
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl Reader<'_> {
+    fn take(&mut self, length: usize) -> Result<&[u8], EnvelopeError> {
+        let end = self.position.checked_add(length)
+            .filter(|it| *it <= self.data.len())
+            .ok_or(EnvelopeError::Invalid)?;
+        let taken = &self.data[self.position..end];
+        self.position = end;
+        Ok(taken)
+    }
+
+    fn peek(&self) -> Result<u8, EnvelopeError> {
+        self.data.get(self.position).copied().ok_or(EnvelopeError::Invalid)
+    }
+
+    /// Read a data item head as its major type and argument
+    fn head(&mut self) -> Result<(u8, u64), EnvelopeError> {
+        let initial = self.take(1)?[0];
+        let major = initial >> 5;
+
+        let value = match initial & 0x1f {
+            value @ 0..=23 => value as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => u16::from_be_bytes(self.take(2)?.try_into().unwrap()) as u64,
+            26 => u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as u64,
+            27 => u64::from_be_bytes(self.take(8)?.try_into().unwrap()),
+            // Indefinite lengths are never produced
+            _ => return Err(EnvelopeError::Invalid)
+        };
+
+        Ok((major, value))
+    }
+
+    fn expect(&mut self, major: u8) -> Result<u64, EnvelopeError> {
+        match self.head()? {
+            (actual, value) if actual == major => Ok(value),
+            _ => Err(EnvelopeError::Invalid)
+        }
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, EnvelopeError> {
+        let length = self.expect(MAJOR_BYTES)?;
+        Ok(self.take(usize::try_from(length).map_err(|_| EnvelopeError::Invalid)?)?.to_vec())
+    }
+
+    fn text(&mut self) -> Result<String, EnvelopeError> {
+        let length = self.expect(MAJOR_TEXT)?;
+        let text = self.take(usize::try_from(length).map_err(|_| EnvelopeError::Invalid)?)?;
+        String::from_utf8(text.to_vec()).map_err(|_| EnvelopeError::Invalid)
+    }
+
+    /// Consume a null item if it is the next one
+    fn null(&mut self) -> Result<bool, EnvelopeError> {
+        let null = self.peek()? == SIMPLE_NULL;
+        if null {
+            self.position += 1;
+        }
+        Ok(null)
+    }
+
+    fn optional_text(&mut self) -> Result<Option<String>, EnvelopeError> {
+        if self.null()? {
+            Ok(None)
+        } else {
+            self.text().map(Some)
+        }
+    }
+
+    fn bool(&mut self) -> Result<bool, EnvelopeError> {
+        match self.take(1)?[0] {
+            SIMPLE_FALSE => Ok(false),
+            SIMPLE_TRUE => Ok(true),
+            _ => Err(EnvelopeError::Invalid)
+        }
+    }
+
+    /// Skip a whole data item
+    fn skip(&mut self) -> Result<(), EnvelopeError> {
+        self.skip_nested(MAX_SKIP_DEPTH)
+    }
+
+    fn skip_nested(&mut self, depth: usize) -> Result<(), EnvelopeError> {
+        let depth = depth.checked_sub(1).ok_or(EnvelopeError::Invalid)?;
+
+        let (major, value) = self.head()?;
+        match major {
+            MAJOR_BYTES | MAJOR_TEXT => {
+                self.take(usize::try_from(value).map_err(|_| EnvelopeError::Invalid)?)?;
+            },
+            MAJOR_ARRAY => for _ in 0..value {
+                self.skip_nested(depth)?;
+            },
+            MAJOR_MAP => for _ in 0..value {
+                self.skip_nested(depth)?;
+                self.skip_nested(depth)?;
+            },
+            MAJOR_TAG => self.skip_nested(depth)?,
+            // Integers and simple values have no content
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(body: Vec<u8>) -> Envelope {
+        Envelope {
+            from: Some("alice@node1".to_owned()),
+            envid: Some("QQ314159".to_owned()),
+            return_full: true,
+            report_to: Some("dtn://node1/mail/report".to_owned()),
+            recipients: vec![
+                EnvelopeRecipient {
+                    address: "bob@node2".to_owned(),
+                    notify: NotifyConditions { success: true, failure: true, delay: false },
+                    orcpt: Some(("rfc822".to_owned(), "bob@example.org".to_owned())),
+                    report_id: Some("1.1@node1".to_owned())
+                },
+                EnvelopeRecipient {
+                    address: "carol@node2".to_owned(),
+                    notify: NotifyConditions::default(),
+                    orcpt: None,
+                    report_id: None
+                }
+            ],
+            body
+        }
+    }
+
+    /// Encoded envelope with `extra` items appended to the top level array
+    fn with_trailing_items(envelope: &Envelope, extra: &[u8], count: u8) -> Vec<u8> {
+        let mut bytes = envelope.to_bytes();
+        assert_eq!(bytes[3], 0x87);
+        bytes[3] = 0x87 + count;
+        bytes.extend_from_slice(extra);
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let envelope = envelope(b"Subject: Test\r\n\r\nHello\r\n".to_vec());
+        assert_eq!(Envelope::from_bytes(&envelope.to_bytes()).unwrap(), envelope);
+    }
+
+    #[test]
+    fn round_trip_null_sender() {
+        let envelope = Envelope { from: None, envid: None, return_full: false, report_to: None, ..envelope(Vec::new()) };
+        assert_eq!(Envelope::from_bytes(&envelope.to_bytes()).unwrap(), envelope);
+    }
+
+    #[test]
+    fn round_trip_empty_body() {
+        let envelope = envelope(Vec::new());
+        assert_eq!(Envelope::from_bytes(&envelope.to_bytes()).unwrap(), envelope);
+    }
+
+    #[test]
+    fn round_trip_large_body() {
+        let envelope = envelope((0..70_000).map(|it| it as u8).collect());
+        assert_eq!(Envelope::from_bytes(&envelope.to_bytes()).unwrap(), envelope);
+    }
+
+    #[test]
+    fn raw_message_is_not_envelope() {
+        assert!(matches!(Envelope::from_bytes(b"Subject: Test\r\n\r\nHello\r\n"), Err(EnvelopeError::NotEnvelope)));
+        assert!(matches!(Envelope::from_bytes(&[]), Err(EnvelopeError::NotEnvelope)));
+    }
+
+    #[test]
+    fn truncated_envelope_is_invalid() {
+        let bytes = envelope(b"Hello".to_vec()).to_bytes();
+
+        for length in ENVELOPE_MAGIC.len()..bytes.len() {
+            assert!(matches!(Envelope::from_bytes(&bytes[..length]), Err(EnvelopeError::Invalid)), "length {length}");
+        }
+    }
+
+    #[test]
+    fn other_version_is_unsupported() {
+        let mut bytes = envelope(Vec::new()).to_bytes();
+        assert_eq!(bytes[4], ENVELOPE_VERSION as u8);
+        bytes[4] = 2;
+
+        assert!(matches!(Envelope::from_bytes(&bytes), Err(EnvelopeError::UnsupportedVersion(2))));
+    }
+
+    #[test]
+    fn unknown_trailing_items_are_skipped() {
+        let envelope = envelope(b"Hello".to_vec());
+        // Integer, text, array with a map and a tagged byte string
+        let extra = [0x18, 0x2a, 0x62, b'h', b'i', 0x82, 0xa1, 0x01, 0xf6, 0xc2, 0x41, 0x00];
+
+        assert_eq!(Envelope::from_bytes(&with_trailing_items(&envelope, &extra, 3)).unwrap(), envelope);
+    }
+
+    #[test]
+    fn deeply_nested_trailing_item_is_invalid() {
+        let envelope = envelope(Vec::new());
+
+        let mut nested = vec![0x81; 100_000];
+        nested.push(0x00);
+        assert!(matches!(Envelope::from_bytes(&with_trailing_items(&envelope, &nested, 1)), Err(EnvelopeError::Invalid)));
+
+        let mut nested = vec![0x81; MAX_SKIP_DEPTH - 1];
+        nested.push(0x00);
+        assert_eq!(Envelope::from_bytes(&with_trailing_items(&envelope, &nested, 1)).unwrap(), envelope);
+    }
+}
@@ -1,31 +1,397 @@
-use std::sync::mpsc::Receiver;
+use std::{collections::VecDeque, io, path::Path, sync::{mpsc::{self, Receiver, RecvTimeoutError, Sender}, Arc, Mutex}, thread, time::{Duration, Instant, SystemTime}};
 
-use log::debug;
+use log::{debug, error, info, warn};
+use thiserror::Error;
+use ud3tn_aap::Agent;
 
-use crate::{defaults::INBOX_AGENT_ID, smtp::Mail};
+use crate::{defaults::{INBOX_AGENT_ID, OUTBOX_AGENT_ID}, dsn::{notify_conditions, ReportTracker}, envelope::{Envelope, EnvelopeRecipient, NotifyConditions}, smtp::{BadAddressError, DsnNotify, DsnReturn, EmailAddress, Mail, MailParameters, OriginalRecipient, RcptParameters, Recipient}, spool::{Spool, SpoolError}};
+
+pub const DEFAULT_SPOOL_DIRECTORY: &str = "/var/spool/ddelivery";
+
+/// Delay before reconnecting to archipel-core, doubled at each consecutive failure
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Delay before sending again a mail refused by archipel-core, doubled at each attempt
+const MIN_MAIL_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_MAIL_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Number of refused attempts after which a mail is given up
+const MAX_SEND_ATTEMPTS: u32 = 8;
+
+/// Mail stored in the spool until its bundles are handed to archipel-core
+pub struct SpooledMail {
+    pub id: String,
+    /// When the mail was stored, reported as its arrival if it is given up
+    pub stored: SystemTime,
+    pub mail: Mail
+}
+
+/// Mail waiting in the queue of the sender task
+struct QueuedMail {
+    spooled: SpooledMail,
+    /// Attempts refused while archipel-core was reachable
+    attempts: u32,
+    next_attempt: Instant
+}
 
 pub enum SenderMsg {
-    SendMail(Mail),
+    SendMail(SpooledMail),
     ShutdownTask
 }
 
-pub fn run_sender_task(receiver: Receiver<SenderMsg>, mut outbox_agent: ud3tn_aap::Agent){
+#[derive(Debug, Error)]
+pub enum SubmissionError {
+    #[error("Failed to store mail in spool : {0}")]
+    Spool(#[from] SpoolError),
+    #[error("Mail sender task unavailable")]
+    SenderUnavailable,
+    #[error("Mail submission timed out")]
+    Timeout
+}
+
+#[derive(Debug, Error)]
+enum SendError {
+    #[error("Lost connection to archipel-core while sending mail to node {0} : {1}")]
+    Connection(String, String),
+    #[error("archipel-core refused mail to node {0} : {1}")]
+    Refused(String, String)
+}
+
+/// Store a mail in the spool and queue it for sending, giving up after `timeout` if any,
+/// the mail is safe once this returns
+///
+/// A mail stored after the timeout is removed from the spool, the client having been
+/// told to send it again.
+pub fn submit_mail(sender_channel: &Sender<SenderMsg>, spool: &Arc<Spool>, mail: Mail, timeout: Option<Duration>) -> Result<(), SubmissionError> {
+    let Some(timeout) = timeout else {
+        return queue_mail(sender_channel, spool_mail(spool, mail)?);
+    };
+
+    let abandoned = Arc::new(Mutex::new(false));
+    let (reply_sender, reply_receiver) = mpsc::channel();
+
+    {
+        let sender_channel = sender_channel.clone();
+        let spool = spool.clone();
+        let abandoned = abandoned.clone();
+
+        thread::spawn(move || {
+            let result = spool_mail(&spool, mail);
+
+            // Held until the reply is sent so that the session sees either the reply or its own timeout
+            let abandoned = abandoned.lock().unwrap();
+            let reply = match result {
+                Ok(spooled) if *abandoned => {
+                    if let Err(e) = spool.remove(&spooled.id) {
+                        error!("Failed to remove abandoned mail {} from spool : {e}", spooled.id);
+                    }
+                    return;
+                },
+                Ok(spooled) => queue_mail(&sender_channel, spooled),
+                Err(e) => Err(e.into())
+            };
+
+            let _ = reply_sender.send(reply);
+        });
+    }
+
+    match reply_receiver.recv_timeout(timeout) {
+        Ok(reply) => reply,
+        Err(RecvTimeoutError::Disconnected) => Err(SubmissionError::SenderUnavailable),
+        Err(RecvTimeoutError::Timeout) => {
+            let mut abandoned = abandoned.lock().unwrap();
+            // The mail may have been queued while waiting for the lock
+            reply_receiver.try_recv().unwrap_or_else(|_| {
+                *abandoned = true;
+                Err(SubmissionError::Timeout)
+            })
+        }
+    }
+}
+
+fn queue_mail(sender_channel: &Sender<SenderMsg>, spooled: SpooledMail) -> Result<(), SubmissionError> {
+    sender_channel.send(SenderMsg::SendMail(spooled))
+        .map_err(|_| SubmissionError::SenderUnavailable)
+}
+
+fn spool_mail(spool: &Spool, mail: Mail) -> Result<SpooledMail, SpoolError> {
+    let id = spool.store(&mail.content, &spool_envelope(&mail))?;
+    Ok(SpooledMail { id, stored: SystemTime::now(), mail })
+}
+
+/// Load the mails left in the spool by a previous run, oldest first, moving those
+/// that cannot be read back to the dead-letter directory
+pub fn load_spooled_mails(spool: &Spool) -> Result<Vec<SpooledMail>, SpoolError> {
+    Ok(spool.load_all()?
+        .into_iter()
+        .filter_map(|(id, stored, envelope)| match spooled_mail(envelope) {
+            Ok(mail) => Some(SpooledMail { id, stored, mail }),
+            Err(e) => {
+                error!("Invalid address in spooled mail {id}, moving it to dead letters : {e}");
+                if let Err(e) = spool.dead_letter(&id) {
+                    error!("Failed to move spooled mail {id} to dead letters : {e}");
+                }
+                None
+            }
+        })
+        .collect())
+}
+
+/// Envelope recording the recipients of a mail that are still to be sent
+fn spool_envelope(mail: &Mail) -> Envelope {
+    Envelope {
+        from: match &mail.from {
+            EmailAddress::Null => None,
+            from => Some(from.address().to_owned())
+        },
+        envid: mail.parameters.envid.clone(),
+        return_full: mail.parameters.ret == Some(DsnReturn::Full),
+        report_to: None,
+        recipients: mail.receipients.iter()
+            .map(|recipient| EnvelopeRecipient {
+                address: recipient.address.address().to_owned(),
+                notify: {
+                    let notify = notify_conditions(&recipient.parameters);
+                    NotifyConditions { success: notify.success, failure: notify.failure, delay: notify.delay }
+                },
+                orcpt: recipient.parameters.orcpt.as_ref()
+                    .map(|it| (it.address_type.clone(), it.address.clone())),
+                report_id: None
+            })
+            .collect(),
+        body: Vec::new()
+    }
+}
+
+/// Mail read back from its spooled envelope
+fn spooled_mail(envelope: Envelope) -> Result<Mail, BadAddressError> {
+    let parse_address = |address: &str| EmailAddress::from_bytes(format!("<{address}>").into_bytes());
+
+    let mut mail = Mail::new(parse_address(envelope.from.as_deref().unwrap_or_default())?, MailParameters {
+        envid: envelope.envid,
+        ret: envelope.return_full.then_some(DsnReturn::Full),
+        ..Default::default()
+    });
+
+    for recipient in envelope.recipients {
+        mail.receipients.push(Recipient {
+            address: parse_address(&recipient.address)?,
+            parameters: RcptParameters {
+                notify: Some(DsnNotify {
+                    success: recipient.notify.success,
+                    failure: recipient.notify.failure,
+                    delay: recipient.notify.delay
+                }),
+                orcpt: recipient.orcpt
+                    .map(|(address_type, address)| OriginalRecipient { address_type, address })
+            }
+        });
+    }
+
+    mail.content = envelope.body;
+
+    Ok(mail)
+}
+
+pub fn run_sender_task(receiver: Receiver<SenderMsg>, outbox_agent: Agent, aap_socket: &Path, report_tracker: Arc<ReportTracker>, spool: Arc<Spool>){
     debug!("Starting mail sender task");
 
-    for msg in receiver {
+    let mut queue: VecDeque<QueuedMail> = VecDeque::new();
+    // Dropped as soon as the connection is lost, archipel-core refusing to register
+    // the outbox again while it is still held
+    let mut outbox_agent = Some(outbox_agent);
+    let mut retry_delay = MIN_RETRY_DELAY;
+    let mut next_reconnect = Instant::now();
+
+    loop {
+        let next_wakeup = match outbox_agent {
+            Some(_) => queue.iter().map(|it| it.next_attempt).min(),
+            None => Some(next_reconnect)
+        };
+
+        let msg = match next_wakeup {
+            Some(wakeup) => receiver.recv_timeout(wakeup.saturating_duration_since(Instant::now())),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        };
+
         match msg {
-            SenderMsg::ShutdownTask => break,
-            SenderMsg::SendMail(mail) => {
-                for recipient in mail.receipients.into_iter() {
-                    let detination = format!("dtn://{}/{}", recipient.domain(), INBOX_AGENT_ID);
-                    debug!("Sending mail to {detination}");
-
-                    outbox_agent.send_bundle(
-                        detination,
-                        &mail.content
-                    ).expect("Failed to send mail to node");
+            Ok(SenderMsg::SendMail(spooled)) => queue.push_back(QueuedMail { spooled, attempts: 0, next_attempt: Instant::now() }),
+            Ok(SenderMsg::ShutdownTask) | Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+
+        if outbox_agent.is_none() {
+            // Mails received while waiting to reconnect are sent with the others
+            if next_reconnect > Instant::now() {
+                continue;
+            }
+
+            match Agent::connect_unix(aap_socket, OUTBOX_AGENT_ID.to_owned()) {
+                Ok(agent) => {
+                    info!("Outbox reconnected to archipel-core");
+                    outbox_agent = Some(agent);
+                    retry_delay = MIN_RETRY_DELAY;
+                },
+                Err(e) => {
+                    error!("Failed to reconnect to archipel-core : {e}");
+                    warn!("{} mail(s) waiting to be sent, reconnecting in {}s", queue.len(), retry_delay.as_secs());
+                    next_reconnect = Instant::now() + retry_delay;
+                    retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                    continue;
                 }
+            }
+        }
+
+        while let Some(agent) = outbox_agent.as_mut() {
+            let Some(position) = queue.iter().position(|it| it.next_attempt <= Instant::now()) else {
+                break;
+            };
+            let mut queued = queue.remove(position).unwrap();
+
+            match send_mail(agent, &report_tracker, &spool, &mut queued.spooled) {
+                Ok(()) => if let Err(e) = spool.remove(&queued.spooled.id) {
+                    error!("Failed to remove sent mail {} from spool : {e}", queued.spooled.id);
+                },
+                // A lost connection fails every mail, which keeps its place in the queue
+                Err(e @ SendError::Connection(..)) => {
+                    error!("{e}");
+                    queue.insert(position, queued);
+                    warn!("{} mail(s) waiting to be sent, reconnecting in {}s", queue.len(), retry_delay.as_secs());
+                    outbox_agent = None;
+                    next_reconnect = Instant::now() + retry_delay;
+                    retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                },
+                // A refused bundle only concerns this mail, which is moved behind the others
+                Err(e @ SendError::Refused(..)) => {
+                    error!("{e}");
+                    queued.attempts += 1;
+
+                    if queued.attempts >= MAX_SEND_ATTEMPTS {
+                        for spooled in give_up(&report_tracker, &spool, queued.spooled, &e.to_string()) {
+                            queue.push_back(QueuedMail { spooled, attempts: 0, next_attempt: Instant::now() });
+                        }
+                    } else {
+                        let delay = MIN_MAIL_RETRY_DELAY.saturating_mul(2u32.saturating_pow(queued.attempts - 1))
+                            .min(MAX_MAIL_RETRY_DELAY);
+                        warn!("Mail {} refused, retrying in {}s", queued.spooled.id, delay.as_secs());
+                        queued.next_attempt = Instant::now() + delay;
+                        queue.push_back(queued);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Give up a mail refused too many times, moving it to the dead-letter directory and
+/// returning the spooled failure notifications for its recipients left
+fn give_up(report_tracker: &ReportTracker, spool: &Spool, spooled: SpooledMail, diagnostic: &str) -> Vec<SpooledMail> {
+    error!("Giving up mail {} after {MAX_SEND_ATTEMPTS} attempts, moving it to dead letters", spooled.id);
+
+    let notifications = spooled.mail.receipients.iter()
+        .filter_map(|recipient| report_tracker.unsent(&spooled.mail, recipient, spooled.stored, diagnostic))
+        .filter_map(|notification| match spool_mail(spool, notification) {
+            Ok(notification) => Some(notification),
+            Err(e) => {
+                error!("Failed to store failure notification of mail {} : {e}", spooled.id);
+                None
+            }
+        })
+        .collect();
+
+    if let Err(e) = spool.dead_letter(&spooled.id) {
+        error!("Failed to move mail {} to dead letters : {e}", spooled.id);
+    }
+
+    notifications
+}
+
+fn destination_of(recipient: &Recipient, node_eid: &str) -> String {
+    match recipient.address.domain() {
+        Some(domain) => format!("dtn://{domain}/{INBOX_AGENT_ID}"),
+        // Unqualified postmaster is the one of this node
+        None => format!("{node_eid}{INBOX_AGENT_ID}")
+    }
+}
+
+/// Send one bundle per destination node, removing recipients from the spooled mail once sent
+fn send_mail(outbox_agent: &mut Agent, report_tracker: &ReportTracker, spool: &Spool, spooled: &mut SpooledMail) -> Result<(), SendError> {
+    let mail = &mut spooled.mail;
+
+    let mut destinations: Vec<String> = Vec::new();
+    for recipient in mail.receipients.iter() {
+        let destination = destination_of(recipient, &outbox_agent.node_eid);
+        if !destinations.contains(&destination) {
+            destinations.push(destination);
+        }
+    }
+
+    for destination in destinations {
+        let recipients = mail.receipients.iter()
+            .filter(|it| destination_of(it, &outbox_agent.node_eid) == destination)
+            .collect::<Vec<_>>();
+
+        debug!("Sending mail to {destination} for {} recipient(s)", recipients.len());
+
+        let mut report_ids = Vec::new();
+        let mut envelope_recipients = Vec::new();
+        for recipient in recipients {
+            let report_id = report_tracker.register(mail, recipient);
+            let notify = notify_conditions(&recipient.parameters);
+            report_ids.extend(report_id.clone());
+
+            envelope_recipients.push(EnvelopeRecipient {
+                address: recipient.address.address().to_owned(),
+                notify: NotifyConditions { success: notify.success, failure: notify.failure, delay: notify.delay },
+                orcpt: recipient.parameters.orcpt.as_ref()
+                    .map(|it| (it.address_type.clone(), it.address.clone())),
+                report_id
+            });
+        }
+
+        let envelope = Envelope {
+            from: match &mail.from {
+                EmailAddress::Null => None,
+                from => Some(from.address().to_owned())
             },
+            envid: mail.parameters.envid.clone(),
+            return_full: mail.parameters.ret == Some(DsnReturn::Full),
+            report_to: (!report_ids.is_empty()).then(|| report_tracker.report_to().to_owned()),
+            recipients: envelope_recipients,
+            body: mail.content.clone()
+        };
+
+        if let Err(e) = outbox_agent.send_bundle(destination.clone(), &envelope.to_bytes()) {
+            for id in report_ids {
+                report_tracker.cancel(&id);
+            }
+            return Err(match is_connection_error(&e) {
+                true => SendError::Connection(destination, e.to_string()),
+                false => SendError::Refused(destination, e.to_string())
+            });
+        }
+
+        mail.receipients.retain(|it| destination_of(it, &outbox_agent.node_eid) != destination);
+        if !mail.receipients.is_empty() {
+            if let Err(e) = spool.update(&spooled.id, &spool_envelope(mail)) {
+                error!("Failed to update spooled mail {} : {e}", spooled.id);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether archipel-core could not be reached, an I/O error being the cause of the
+/// failure, rather than the bundle being refused
+fn is_connection_error(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut cause = Some(error);
+    while let Some(error) = cause {
+        if error.is::<io::Error>() {
+            return true;
         }
+        cause = error.source();
     }
-}
\ No newline at end of file
+    false
+}
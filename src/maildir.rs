@@ -0,0 +1,90 @@
+use std::{fs::{self, File}, io::{self, Write}, path::{Path, PathBuf}, process, sync::atomic::{AtomicU64, Ordering}, time::{SystemTime, UNIX_EPOCH}};
+
+use thiserror::Error;
+
+/// Mailboxes of local users, `%u` being replaced by the user name
+pub const DEFAULT_MAILDIR_PATH: &str = "/var/mail/%u";
+
+#[derive(Debug, Error)]
+pub enum MaildirError {
+    #[error("Invalid mailbox name {0:?}")]
+    InvalidUser(String),
+    #[error("No mailbox for user {0}")]
+    NoMailbox(String),
+    #[error("Failed to write message : {0}")]
+    Io(#[from] io::Error)
+}
+
+/// Deliver messages directly in the Maildir of each local user
+///
+/// The Maildir of a user is found by replacing `%u` in the path with its name, or
+/// by appending the name to the path. It has to exist, its `tmp`, `new` and `cur`
+/// subdirectories being created if needed.
+pub struct Maildir {
+    path: String,
+    /// Host part of the message file names, unique to this node
+    host: String,
+    counter: AtomicU64
+}
+
+impl Maildir {
+    pub fn new(path: String, host: &str) -> Self {
+        Self {
+            path,
+            // Characters of the host that would be confused with the file name structure
+            host: host.replace('/', "\\057").replace(':', "\\072"),
+            counter: AtomicU64::new(0)
+        }
+    }
+
+    fn user_directory(&self, user: &str) -> Result<PathBuf, MaildirError> {
+        if user.is_empty() || user.starts_with('.') || user.contains(['/', '\0']) {
+            return Err(MaildirError::InvalidUser(user.to_owned()));
+        }
+
+        let directory = match self.path.contains("%u") {
+            true => PathBuf::from(self.path.replace("%u", user)),
+            false => PathBuf::from(&self.path).join(user)
+        };
+
+        if !directory.is_dir() {
+            return Err(MaildirError::NoMailbox(user.to_owned()));
+        }
+
+        Ok(directory)
+    }
+
+    /// Write a message in `tmp` then move it to `new` so that readers never see it partially written
+    pub fn deliver(&self, user: &str, from: &str, message: &[u8]) -> Result<(), MaildirError> {
+        let directory = self.user_directory(user)?;
+        for subdirectory in ["tmp", "new", "cur"] {
+            fs::create_dir_all(directory.join(subdirectory))?;
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let name = format!("{}.M{}P{}Q{}.{}",
+            now.as_secs(),
+            now.subsec_micros(),
+            process::id(),
+            self.counter.fetch_add(1, Ordering::SeqCst),
+            self.host
+        );
+
+        let temporary_path = directory.join("tmp").join(&name);
+
+        if let Err(e) = write_message(&temporary_path, from, message) {
+            let _ = fs::remove_file(&temporary_path);
+            return Err(e.into());
+        }
+
+        Ok(fs::rename(&temporary_path, directory.join("new").join(&name))?)
+    }
+}
+
+fn write_message(path: &Path, from: &str, message: &[u8]) -> io::Result<()> {
+    let mut file = File::create_new(path)?;
+    // Envelope sender recorded by the final delivery (RFC 5321 section 4.4)
+    file.write_all(format!("Return-Path: <{from}>\r\n").as_bytes())?;
+    file.write_all(message)?;
+    file.sync_all()
+}
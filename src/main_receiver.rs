@@ -1,54 +1,295 @@
+mod bounce;
+mod config;
 mod defaults;
+mod delivery;
+mod domain;
+mod envelope;
+mod maildir;
+mod report;
+mod spool;
 
-use std::{env, path::Path};
+use std::{env, path::{Path, PathBuf}, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
 
-use defaults::INBOX_AGENT_ID;
-use mail_parser::MessageParser;
-use mail_send::{SmtpClient, SmtpClientBuilder};
+use bounce::{bounce, BouncedRecipient};
+use config::{Config, DEFAULT_CONFIG_FILE};
+use defaults::{DEFAULT_MAX_DELIVERY_AGE, INBOX_AGENT_ID};
+use delivery::{Delivery, DeliveryTarget};
+use domain::{is_valid_local_part, node_name};
+use envelope::{Envelope, EnvelopeError, EnvelopeRecipient, NotifyConditions};
+use maildir::MaildirError;
+use mail_parser::{DateTime, MessageParser};
+use report::{Diagnostic, DiagnosticType};
 use simple_logger::SimpleLogger;
-use log::{debug, error, warn};
-use tokio::{io::{AsyncRead, AsyncWrite}, sync::mpsc::{UnboundedReceiver, UnboundedSender}};
+use spool::Spool;
+use log::{debug, error, info, warn};
+use tokio::{sync::mpsc::{UnboundedReceiver, UnboundedSender}, time::Instant};
 use ud3tn_aap::Agent;
 
+const RECEIPT_AGENT_ID:&str = "mail/receipt";
+
+/// Header fields naming the final recipient of legacy messages, in addition to To and Cc
+const DELIVERY_HEADERS: [&str; 2] = ["Delivered-To", "X-Original-To"];
+
+/// Delay before retrying a delivery after a transient failure, doubled at each attempt
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+const DEFAULT_INBOX_SPOOL_DIRECTORY: &str = "/var/spool/ddelivery-receiver";
+
 struct ReceivedMessage {
     raw_message: Vec<u8>,
-    recipient_users: Vec<String>,
+    recipients: Vec<LocalRecipient>,
     from: String
 }
 
+impl ReceivedMessage {
+    /// Envelope recording the recipients still to be delivered, the message being spooled apart
+    fn spool_envelope(&self) -> Envelope {
+        Envelope {
+            from: Some(self.from.clone()),
+            envid: None,
+            return_full: false,
+            // Reports of a message are all sent to the node that sent it
+            report_to: self.recipients.iter()
+                .find_map(|it| it.report.as_ref())
+                .map(|it| it.report_to.clone()),
+            recipients: self.recipients.iter()
+                .map(|recipient| EnvelopeRecipient {
+                    address: recipient.user.clone(),
+                    notify: NotifyConditions { success: false, failure: recipient.bounce, delay: false },
+                    orcpt: None,
+                    report_id: recipient.report.as_ref().map(|it| it.id.clone())
+                })
+                .collect(),
+            body: Vec::new()
+        }
+    }
+
+    /// Message read back from its spooled envelope
+    fn from_spool(envelope: Envelope) -> Self {
+        let recipients = envelope.recipients.iter()
+            .map(|recipient| LocalRecipient {
+                user: recipient.address.clone(),
+                report: ReportRequest::for_recipient(&envelope, recipient),
+                bounce: recipient.notify.failure
+            })
+            .collect();
+
+        Self {
+            raw_message: envelope.body,
+            recipients,
+            from: envelope.from.unwrap_or_default()
+        }
+    }
+}
+
+/// Received message kept in the spool until it is delivered
+struct SpooledMessage {
+    id: String,
+    /// When the message was stored, for giving up its delivery
+    stored: SystemTime,
+    message: ReceivedMessage
+}
+
+struct LocalRecipient {
+    user: String,
+    report: Option<ReportRequest>,
+    /// Whether the sender is notified of a permanent failure with a bounce,
+    /// when no delivery report is awaited by its node
+    bounce: bool
+}
+
+/// Received message waiting to be delivered again after a transient failure
+struct PendingDelivery {
+    spooled: SpooledMessage,
+    attempts: u32,
+    next_attempt: Instant
+}
+
+#[derive(Clone)]
+enum DeliveryError {
+    Transient(String),
+    Permanent {
+        /// Enhanced status code of the failure (RFC 3463)
+        status: String,
+        diagnostic: Diagnostic
+    }
+}
+
+impl From<mail_send::Error> for DeliveryError {
+    fn from(e: mail_send::Error) -> Self {
+        match e {
+            mail_send::Error::UnexpectedReply(response) if response.code >= 500 => {
+                let status = match response.esc {
+                    [0, 0, 0] => "5.0.0".to_owned(),
+                    [class, subject, detail] => format!("{class}.{subject}.{detail}")
+                };
+                DeliveryError::Permanent {
+                    diagnostic: Diagnostic {
+                        code_type: DiagnosticType::Smtp,
+                        text: format!("{} {status} {}", response.code, response.message)
+                    },
+                    status
+                }
+            },
+            mail_send::Error::UnexpectedReply(response) =>
+                DeliveryError::Transient(format!("{} {}", response.code, response.message)),
+            e => DeliveryError::Transient(e.to_string())
+        }
+    }
+}
+
+impl From<MaildirError> for DeliveryError {
+    fn from(e: MaildirError) -> Self {
+        match e {
+            MaildirError::InvalidUser(_) => DeliveryError::Permanent {
+                status: "5.1.3".to_owned(),
+                diagnostic: Diagnostic { code_type: DiagnosticType::DDelivery, text: e.to_string() }
+            },
+            MaildirError::NoMailbox(_) => DeliveryError::Permanent {
+                status: "5.1.1".to_owned(),
+                diagnostic: Diagnostic { code_type: DiagnosticType::DDelivery, text: e.to_string() }
+            },
+            MaildirError::Io(_) => DeliveryError::Transient(e.to_string())
+        }
+    }
+}
+
+/// Delivery report awaited by the sending node, which decides whether to notify the sender
+struct ReportRequest {
+    id: String,
+    report_to: String
+}
+
+impl ReportRequest {
+    /// Report requested for an envelope recipient, if any
+    fn for_recipient(envelope: &Envelope, recipient: &EnvelopeRecipient) -> Option<Self> {
+        Some(Self {
+            id: recipient.report_id.clone()?,
+            report_to: envelope.report_to.clone()?
+        })
+    }
+
+    /// Send the delivery report to the sending node, whatever the outcome so that it stops awaiting it
+    fn report(&self, report_sender: &UnboundedSender<(String, Vec<u8>)>, result: Result<(), String>) {
+        let content = match result {
+            Ok(()) => format!("{} delivered", self.id),
+            Err(diagnostic) => format!("{} failed {diagnostic}", self.id)
+        };
+
+        report_sender.send((self.report_to.clone(), content.into_bytes()))
+            .expect("Failed to transmit delivery report to report sender");
+    }
+}
+
+/// Send the delivery reports of all recipients of a message for the same outcome
+fn report_all(recipients: &[LocalRecipient], report_sender: &UnboundedSender<(String, Vec<u8>)>, result: Result<(), String>) {
+    for report in recipients.iter().filter_map(|it| it.report.as_ref()) {
+        report.report(report_sender, result.clone());
+    }
+}
+
+/// User name of an address on this node, unqualified addresses being the postmaster
+fn local_user(address: &str, recipient_domain: &str) -> Option<String> {
+    match address.rsplit_once('@') {
+        Some((username, domain)) => node_name(domain)
+            .is_some_and(|it| it == recipient_domain)
+            .then(|| username.to_owned()),
+        None => address.eq_ignore_ascii_case("postmaster").then(|| "postmaster".to_owned())
+    }
+}
+
+/// Check an address received from another node before it is written in LMTP or SMTP
+/// commands and header fields, refusing the spaces and angle brackets that quoted local
+/// parts would otherwise allow
+fn is_valid_address(address: &str) -> bool {
+    if address.chars().any(|it| it.is_whitespace() || it.is_control() || it == '<' || it == '>') {
+        return false;
+    }
+
+    let Some((local_part, domain)) = address.rsplit_once('@') else {
+        return false;
+    };
+
+    let is_address_literal = domain.starts_with('[') && domain.ends_with(']');
+    is_valid_local_part(local_part) && (is_address_literal || node_name(domain).is_some())
+}
+
 #[tokio::main]
 async fn main() {
     SimpleLogger::new().init()
         .expect("Failed to start log system");
 
+    let aap_socket = env::var("ARCHIPEL_CORE_AAP_SOCKET")
+        .unwrap_or("/run/archipel-core/archipel-core.socket".to_owned());
+
     let inbox_agent = ud3tn_aap::Agent::connect_unix(
-        Path::new(
-            env::var("ARCHIPEL_CORE_AAP_SOCKET")
-            .unwrap_or("/run/archipel-core/archipel-core.socket".to_owned())
-            .as_str()
-        ),
+        Path::new(aap_socket.as_str()),
         INBOX_AGENT_ID.to_owned()
     ).expect("Failed to connect to archipel-core");
 
-    let sender = SmtpClientBuilder::new("localhost", 24)
-        .lmtp(true)
-        .connect_plain()
-        .await.expect("Failed to connect to LMTP server");
+    let receipt_agent = ud3tn_aap::Agent::connect_unix(
+        Path::new(aap_socket.as_str()),
+        RECEIPT_AGENT_ID.to_owned()
+    ).expect("Failed to connect to archipel-core");
+
+    let config = match env::var("DDELIVERY_RECEIVER_CONFIG") {
+        Ok(path) => Config::load(Path::new(&path)).expect("Failed to load receiver configuration"),
+        Err(_) if Path::new(DEFAULT_CONFIG_FILE).is_file() => Config::load(Path::new(DEFAULT_CONFIG_FILE))
+            .expect("Failed to load receiver configuration"),
+        Err(_) => Config::default()
+    };
+
+    let delivery_target = DeliveryTarget::from_config(&config)
+        .expect("Invalid delivery configuration");
+
+    let spool_directory = config.get("inbox_spool_directory")
+        .map(PathBuf::from)
+        .unwrap_or(PathBuf::from(DEFAULT_INBOX_SPOOL_DIRECTORY));
+    let dead_letter_directory = config.get("dead_letter_directory")
+        .map(PathBuf::from)
+        .unwrap_or(spool_directory.join("dead-letter"));
+
+    let spool = Arc::new(Spool::open(spool_directory, dead_letter_directory)
+        .expect("Failed to open inbox spool directory"));
+
+    let max_delivery_age = config.parse("max_delivery_age", DEFAULT_MAX_DELIVERY_AGE.as_secs())
+        .map(Duration::from_secs)
+        .expect("Invalid maximum delivery age");
 
     let (inproc_sender, inproc_receiver) = 
-        tokio::sync::mpsc::unbounded_channel::<ReceivedMessage>();
+        tokio::sync::mpsc::unbounded_channel::<SpooledMessage>();
+
+    // Messages received before a restart are delivered first
+    for (id, stored, envelope) in spool.load_all().expect("Failed to read inbox spool directory") {
+        info!("Resuming spooled message {id}");
+        inproc_sender.send(SpooledMessage { id, stored, message: ReceivedMessage::from_spool(envelope) })
+            .expect("Failed to queue spooled message");
+    }
+
+    let (report_sender, report_receiver) =
+        tokio::sync::mpsc::unbounded_channel::<(String, Vec<u8>)>();
     
-    let recipient_domain = inbox_agent.node_eid[6..inbox_agent.node_eid.len()-1].to_owned();
+    let node_eid_name = &inbox_agent.node_eid[6..inbox_agent.node_eid.len()-1];
+    let recipient_domain = node_name(node_eid_name).unwrap_or(node_eid_name.to_owned());
+
+    let sender = Delivery::new(delivery_target, &recipient_domain);
+
+    let dtn_report_sender = report_sender.clone();
+    let dtn_spool = spool.clone();
+    let reporting_domain = recipient_domain.clone();
 
-    let (_, result) = tokio::join!(
-        lmtp_sender_task(sender, inproc_receiver),
-        tokio::task::spawn_blocking(move || dtn_receiver_task(inbox_agent, inproc_sender, recipient_domain))
+    let (_, result, report_result) = tokio::join!(
+        delivery_task(sender, inproc_receiver, report_sender, spool, reporting_domain, max_delivery_age),
+        tokio::task::spawn_blocking(move || dtn_receiver_task(inbox_agent, inproc_sender, dtn_report_sender, recipient_domain, &dtn_spool)),
+        tokio::task::spawn_blocking(move || dtn_report_task(receipt_agent, report_receiver))
     );
 
-    result.unwrap()
+    result.unwrap();
+    report_result.unwrap()
 }
 
-fn dtn_receiver_task(mut dtn_agent: Agent, inproc_sender: UnboundedSender<ReceivedMessage>, recipient_domain: String){
+fn dtn_receiver_task(mut dtn_agent: Agent, inproc_sender: UnboundedSender<SpooledMessage>, report_sender: UnboundedSender<(String, Vec<u8>)>, recipient_domain: String, spool: &Spool){
     
     let parser = MessageParser::default();
      
@@ -61,6 +302,65 @@ fn dtn_receiver_task(mut dtn_agent: Agent, inproc_sender: UnboundedSender<Receiv
             }
         };
 
+        let (envelope, mut bundle) = match Envelope::from_bytes(&bundle) {
+            Ok(mut envelope) => {
+                let body = std::mem::take(&mut envelope.body);
+                (Some(envelope), body)
+            },
+            Err(EnvelopeError::NotEnvelope) => {
+                debug!("Received legacy raw mail from endpoint {source}");
+                (None, bundle)
+            },
+            Err(e) => {
+                error!("Invalid mail envelope received from endpoint {source} : {e}");
+                continue;
+            }
+        };
+
+        let mut recipients: Vec<LocalRecipient> = Vec::new();
+        if let Some(envelope) = &envelope {
+            for recipient in envelope.recipients.iter() {
+                if !is_valid_address(&recipient.address) && !recipient.address.eq_ignore_ascii_case("postmaster") {
+                    warn!("Dropping invalid envelope recipient {:?}", recipient.address);
+                    if let Some(report) = ReportRequest::for_recipient(envelope, recipient) {
+                        report.report(&report_sender, Err("Invalid recipient address".to_owned()));
+                    }
+                    continue;
+                }
+
+                match local_user(&recipient.address, &recipient_domain) {
+                    Some(user) => {
+                        let report = ReportRequest::for_recipient(envelope, recipient);
+                        recipients.push(LocalRecipient {
+                            user,
+                            bounce: report.is_none() && recipient.notify.failure,
+                            report
+                        })
+                    },
+                    None => {
+                        warn!("Dropping envelope recipient {} not on this node", recipient.address);
+                        if let Some(report) = ReportRequest::for_recipient(envelope, recipient) {
+                            report.report(&report_sender, Err("Recipient not on this node".to_owned()));
+                        }
+                    }
+                }
+            }
+
+            if let Some(from) = envelope.from.as_ref().filter(|it| !is_valid_address(it)) {
+                error!("Invalid envelope sender {from:?} received from endpoint {source}");
+                report_all(&recipients, &report_sender, Err("Invalid sender address".to_owned()));
+                continue;
+            }
+        }
+
+        // Record the arrival of the bundle on this node (RFC 5321 section 4.4)
+        let arrival = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let received = format!("Received: from {source}\r\n\tby {recipient_domain} ({}) with DTN;\r\n\t{}\r\n",
+            dtn_agent.node_eid,
+            DateTime::from_timestamp(arrival as i64).to_rfc822()
+        );
+        bundle.splice(0..0, received.into_bytes());
+
         let message = match parser.parse(&bundle){
             Some(m) => {
                 debug!("Received mail from endpoint {source}");
@@ -68,71 +368,293 @@ fn dtn_receiver_task(mut dtn_agent: Agent, inproc_sender: UnboundedSender<Receiv
             },
             None => {
                 error!("Invalid or empty message received from endpoint {source}");
+                report_all(&recipients, &report_sender, Err("Invalid or empty message".to_owned()));
                 continue;
             }
         };
 
-        let Some(from) = message.from()
-            .and_then(|it| it.first())
-            .and_then(|it| it.address.to_owned())
-            .map(|it| it.to_string()) else {
-                warn!("Missing from field in mail");
-                continue;
-        };
-
-        let mut recipients = Vec::new();
-        if let Some(to_addr) = message.to() {
-            for to in to_addr.iter() {
-                let Some(addr) = to.address.to_owned() else {
-                    continue;
+        let from = match envelope {
+            Some(envelope) => envelope.from.unwrap_or_default(),
+            None => {
+                // Legacy raw messages carry no envelope, recipients are taken from the header
+                let Some(from) = message.from()
+                    .and_then(|it| it.first())
+                    .and_then(|it| it.address.to_owned())
+                    .map(|it| it.to_string()) else {
+                        warn!("Missing from field in mail");
+                        continue;
                 };
 
-                let Some((username, domain)) = addr.split_once("@") else {
+                if !is_valid_address(&from) {
+                    warn!("Invalid from field {from:?} in mail received from endpoint {source}");
                     continue;
-                };
+                }
+
+                let mut addresses = [message.to(), message.cc()].into_iter()
+                    .flatten()
+                    .flat_map(|it| it.iter())
+                    .filter_map(|it| it.address.as_ref().map(|it| it.to_string()))
+                    .collect::<Vec<_>>();
 
-                if domain == recipient_domain {
-                    recipients.push(username.to_owned());
+                addresses.extend(message.headers_raw()
+                    .filter(|(name, _)| DELIVERY_HEADERS.iter().any(|it| it.eq_ignore_ascii_case(name)))
+                    .map(|(_, value)| value.trim().trim_start_matches('<').trim_end_matches('>').to_owned()));
+
+                for address in addresses {
+                    if !is_valid_address(&address) {
+                        info!("Dropping invalid recipient {address:?}");
+                        continue;
+                    }
+
+                    // The From field is not a reverse path, bouncing to it would be backscatter
+                    match local_user(&address, &recipient_domain) {
+                        Some(user) => if !recipients.iter().any(|it| it.user == user) {
+                            recipients.push(LocalRecipient { user, report: None, bounce: false });
+                        },
+                        None => info!("Dropping recipient {address} not on this node")
+                    }
                 }
+
+                from
             }
-        }
+        };
 
         drop(message);
 
-        inproc_sender.send(ReceivedMessage {
+        if recipients.is_empty() {
+            warn!("Received mail without local recipient");
+            continue;
+        }
+
+        let message = ReceivedMessage {
             raw_message: bundle,
-            recipient_users: recipients,
+            recipients,
             from
-        }).expect("Failed to transmit message to mail sender");
+        };
+
+        let id = match spool.store(&message.raw_message, &message.spool_envelope()) {
+            Ok(id) => id,
+            Err(e) => {
+                error!("Failed to store mail received from endpoint {source} : {e}");
+                report_all(&message.recipients, &report_sender, Err("Insufficient system storage".to_owned()));
+                continue;
+            }
+        };
+
+        inproc_sender.send(SpooledMessage { id, stored: SystemTime::now(), message })
+            .expect("Failed to transmit message to mail sender");
     }
 }
 
-async fn lmtp_sender_task<T: AsyncRead+AsyncWrite+Unpin>(mut sender: SmtpClient<T>, mut inproc_receiver: UnboundedReceiver<ReceivedMessage>){
-   
+async fn delivery_task(mut sender: Delivery, mut inproc_receiver: UnboundedReceiver<SpooledMessage>, report_sender: UnboundedSender<(String, Vec<u8>)>, spool: Arc<Spool>, recipient_domain: String, max_delivery_age: Duration){
+
+    let mut retries: Vec<PendingDelivery> = Vec::new();
+
     loop {
-        let Some(source_message) = inproc_receiver.recv().await else {
-            break;
+        let next_retry = retries.iter().map(|it| it.next_attempt).min();
+        let next_keepalive = sender.next_keepalive();
+
+        let (mut spooled, attempts) = tokio::select! {
+            received = inproc_receiver.recv() => match received {
+                Some(spooled) => (spooled, 0),
+                None => break
+            },
+            _ = tokio::time::sleep_until(next_retry.unwrap_or_else(Instant::now)), if next_retry.is_some() => {
+                let position = retries.iter()
+                    .position(|it| Some(it.next_attempt) == next_retry)
+                    .unwrap();
+                let pending = retries.swap_remove(position);
+                (pending.spooled, pending.attempts)
+            },
+            _ = tokio::time::sleep_until(next_keepalive.unwrap_or_else(Instant::now)), if next_keepalive.is_some() => {
+                sender.keepalive().await;
+                continue;
+            }
         };
 
-        if source_message.recipient_users.is_empty() {
-            warn!("Received mail without local recipient");
-            continue;
+        let expired = spooled.stored.elapsed().unwrap_or_default() >= max_delivery_age;
+
+        let outcomes = deliver(&mut sender, &spooled.message).await.into_iter()
+            .map(|outcome| match outcome {
+                // Given up so that the sender learns the message will not be delivered
+                Err(DeliveryError::Transient(diagnostic)) if expired => Err(DeliveryError::Permanent {
+                    status: "5.4.7".to_owned(),
+                    diagnostic: Diagnostic {
+                        code_type: DiagnosticType::DDelivery,
+                        text: format!("Delivery time expired : {diagnostic}")
+                    }
+                }),
+                outcome => outcome
+            })
+            .collect::<Vec<_>>();
+
+        let mut delivered = 0;
+        let mut remaining = Vec::new();
+        let mut bounced = Vec::new();
+        let mut retry_diagnostic = None;
+
+        for (recipient, outcome) in spooled.message.recipients.drain(..).zip(outcomes) {
+            match outcome {
+                Ok(()) => {
+                    debug!("Successfully transmitted message {} to {}", spooled.id, recipient.user);
+                    if let Some(report) = &recipient.report {
+                        report.report(&report_sender, Ok(()));
+                    }
+                    delivered += 1;
+                },
+                Err(DeliveryError::Transient(diagnostic)) => {
+                    retry_diagnostic = Some(diagnostic);
+                    remaining.push(recipient);
+                },
+                Err(DeliveryError::Permanent { status, diagnostic }) => {
+                    error!("Failed to transmit message {} to {} : {}", spooled.id, recipient.user, diagnostic.text);
+                    if let Some(report) = &recipient.report {
+                        report.report(&report_sender, Err(diagnostic.to_string()));
+                    }
+                    if recipient.bounce {
+                        bounced.push(BouncedRecipient {
+                            address: format!("{}@{recipient_domain}", recipient.user),
+                            status,
+                            diagnostic
+                        });
+                    }
+                }
+            }
+        }
+        spooled.message.recipients = remaining;
+
+        if !bounced.is_empty() {
+            match bounce(&recipient_domain, &spooled.message.from, &bounced, &spooled.message.raw_message) {
+                Some(bounce) => report_sender.send(bounce)
+                    .expect("Failed to transmit bounce to report sender"),
+                None => warn!("Cannot return bounce of message {} to sender {:?}", spooled.id, spooled.message.from)
+            }
+        }
+
+        if let Some(diagnostic) = retry_diagnostic {
+            let delay = MIN_RETRY_DELAY.saturating_mul(2u32.saturating_pow(attempts)).min(MAX_RETRY_DELAY);
+            warn!("Failed to transmit message {} to {} recipient(s), retrying in {}s : {diagnostic}",
+                spooled.id, spooled.message.recipients.len(), delay.as_secs());
+
+            if let Err(e) = spool.update(&spooled.id, &spooled.message.spool_envelope()) {
+                error!("Failed to update spooled message {} : {e}", spooled.id);
+            }
+
+            retries.push(PendingDelivery {
+                spooled,
+                attempts: attempts + 1,
+                next_attempt: Instant::now() + delay
+            });
+        } else if delivered == 0 {
+            error!("Failed to transmit message {}, moving it to dead letters", spooled.id);
+            if let Err(e) = spool.dead_letter(&spooled.id) {
+                error!("Failed to move message {} to dead letters : {e}", spooled.id);
+            }
+        } else if let Err(e) = spool.remove(&spooled.id) {
+            error!("Failed to remove delivered message {} from spool : {e}", spooled.id);
+        }
+    }
+
+}
+
+/// Deliver a message to local users, giving the outcome for each of its recipients
+async fn deliver(sender: &mut Delivery, message: &ReceivedMessage) -> Vec<Result<(), DeliveryError>> {
+    let recipients = message.recipients.iter()
+        .map(|it| it.user.as_str())
+        .collect::<Vec<_>>();
+
+    let connection = match sender {
+        Delivery::Server(connection) => connection,
+        // Writing to the local disk is short enough to be done without handing it to another thread
+        Delivery::Maildir(maildir) => return tokio::task::block_in_place(|| recipients.iter()
+            .map(|user| maildir.deliver(user, &message.from, &message.raw_message).map_err(DeliveryError::from))
+            .collect())
+    };
+
+    match connection.send(&message.from, &recipients, &message.raw_message).await {
+        Ok(outcomes) => outcomes.into_iter()
+            .map(|it| it.map_err(DeliveryError::from))
+            .collect(),
+        Err(e) => {
+            let error = DeliveryError::from(e);
+            recipients.iter().map(|_| Err(error.clone())).collect()
         }
-        
-        let mut message = mail_send::smtp::message::Message::empty()
-        .body(source_message.raw_message)
-        .from(source_message.from);
+    }
+}
+
+/// Send delivery reports back to the sending nodes
+fn dtn_report_task(mut dtn_agent: Agent, mut report_receiver: UnboundedReceiver<(String, Vec<u8>)>){
+    while let Some((destination, report)) = report_receiver.blocking_recv() {
+        debug!("Sending delivery report to {destination}");
 
-        for recipient in source_message.recipient_users {
-            message = message.to(recipient);
+        if let Err(e) = dtn_agent.send_bundle(destination.clone(), &report) {
+            error!("Failed to send delivery report to {destination} : {e}");
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        match sender.send(message).await {
-            Ok(_) => debug!("Successfully transmitted message"),
-            Err(e) => error!("Failed to transmit message: {e}")
+    #[test]
+    fn valid_addresses_are_accepted() {
+        for address in ["bob@node1", "first.last+tag@node1", "\"bob\"@node1", "bob@[127.0.0.1]", "josé@nœud"] {
+            assert!(is_valid_address(address), "{address}");
         }
+    }
 
-        //BUG Try to reconnect in cas of failed transmission or inactivity
+    #[test]
+    fn addresses_injecting_commands_are_refused() {
+        for address in [
+            "bob@node1>\r\nRCPT TO:<alice",
+            "bob@node1> NOTIFY=NEVER",
+            "\"bob\r\nX-Injected: yes\"@node1",
+            "\"bob> <alice\"@node1",
+            "bob@node1\0",
+            "bob",
+            "@node1",
+            ""
+        ] {
+            assert!(!is_valid_address(address), "{address:?}");
+        }
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn local_users_are_resolved_from_node_name() {
+        assert_eq!(local_user("bob@node1", "node1").as_deref(), Some("bob"));
+        assert_eq!(local_user("bob@NODE1", "node1").as_deref(), Some("bob"));
+        assert_eq!(local_user("bob@node2", "node1"), None);
+        assert_eq!(local_user("Postmaster", "node1").as_deref(), Some("postmaster"));
+    }
+
+    #[test]
+    fn spooled_messages_keep_recipients_and_reports() {
+        let message = ReceivedMessage {
+            raw_message: b"Subject: test\r\n\r\nHello\r\n".to_vec(),
+            recipients: vec![
+                LocalRecipient {
+                    user: "bob".to_owned(),
+                    report: Some(ReportRequest { id: "1.1@node2".to_owned(), report_to: "dtn://node2/mail/report".to_owned() }),
+                    bounce: false
+                },
+                LocalRecipient { user: "carol".to_owned(), report: None, bounce: true }
+            ],
+            from: "alice@node2".to_owned()
+        };
+
+        let mut envelope = Envelope::from_bytes(&message.spool_envelope().to_bytes()).unwrap();
+        envelope.body = message.raw_message.clone();
+        let spooled = ReceivedMessage::from_spool(envelope);
+
+        assert_eq!(spooled.raw_message, message.raw_message);
+        assert_eq!(spooled.from, "alice@node2");
+        assert_eq!(spooled.recipients.len(), 2);
+        let report = spooled.recipients[0].report.as_ref().unwrap();
+        assert_eq!((report.id.as_str(), report.report_to.as_str()), ("1.1@node2", "dtn://node2/mail/report"));
+        assert!(!spooled.recipients[0].bounce);
+        assert_eq!(spooled.recipients[1].user, "carol");
+        assert!(spooled.recipients[1].report.is_none());
+        assert!(spooled.recipients[1].bounce);
+    }
+}
@@ -2,43 +2,147 @@ mod smtp_server;
 mod smtp;
 mod mail_sender;
 mod defaults;
+mod auth;
+mod domain;
+mod dsn;
+mod directory;
+mod envelope;
+mod report;
+mod spool;
 
-use std::{env, path::Path, sync::mpsc, thread};
+use std::{env, path::{Path, PathBuf}, sync::{mpsc, Arc}, thread, time::Duration};
 
+use auth::SenderPolicy;
 use defaults::OUTBOX_AGENT_ID;
-use log::info;
-use mail_sender::run_sender_task;
+use dsn::{run_expiry_task, run_report_task, DsnConfig, ReportTracker, DEFAULT_DELAY_WARNING, DEFAULT_EXPIRY, REPORT_AGENT_ID};
+use log::{info, LevelFilter};
+use mail_sender::{run_sender_task, DEFAULT_SPOOL_DIRECTORY};
 use simple_logger::SimpleLogger;
-use smtp_server::{run_smtp_server, SmtpConfig};
+use smtp::SmtpTimeouts;
+use spool::Spool;
+use smtp_server::{run_smtp_server, AuthConfig, SmtpConfig, TlsConfig, DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_MAX_SESSIONS};
 
 fn main() {
-    SimpleLogger::new().init()
+    SimpleLogger::new()
+        .with_module_level("rustls", LevelFilter::Info)
+        .init()
         .expect("Failed to start log system");
 
+    let aap_socket = env::var("ARCHIPEL_CORE_AAP_SOCKET")
+        .unwrap_or("/run/archipel-core/archipel-core.socket".to_owned());
+
     let outbox_agent = ud3tn_aap::Agent::connect_unix(
-        Path::new(
-            env::var("ARCHIPEL_CORE_AAP_SOCKET")
-            .unwrap_or("/run/archipel-core/archipel-core.socket".to_owned())
-            .as_str()
-        ),
+        Path::new(aap_socket.as_str()),
         OUTBOX_AGENT_ID.to_owned()
     ).expect("Failed to connect to archipel-core");
 
     info!("Outbox connected to archipel-core {}{}", outbox_agent.node_eid, outbox_agent.agent_id);
 
+    let report_agent = ud3tn_aap::Agent::connect_unix(
+        Path::new(aap_socket.as_str()),
+        REPORT_AGENT_ID.to_owned()
+    ).expect("Failed to connect to archipel-core");
+
+    let node_eid = outbox_agent.node_eid.clone();
+
+    let spool_directory = env::var("DDELIVERY_SPOOL_DIRECTORY")
+        .map(PathBuf::from)
+        .unwrap_or(PathBuf::from(DEFAULT_SPOOL_DIRECTORY));
+    let dead_letter_directory = env::var("DDELIVERY_DEAD_LETTER_DIRECTORY")
+        .map(PathBuf::from)
+        .unwrap_or(spool_directory.join("dead-letter"));
+
+    let report_spool = Spool::open(spool_directory.join("reports"), dead_letter_directory.clone())
+        .expect("Failed to open report spool directory");
+    let spool = Arc::new(Spool::open(spool_directory, dead_letter_directory)
+        .expect("Failed to open spool directory"));
+
+    let report_tracker = Arc::new(ReportTracker::new(DsnConfig {
+        delay_warning: env_duration("DDELIVERY_DSN_DELAY_WARNING", DEFAULT_DELAY_WARNING),
+        expiry: env_duration("DDELIVERY_DSN_EXPIRY", DEFAULT_EXPIRY)
+    }, &node_eid, report_spool).expect("Failed to read report spool directory"));
+
     let (sender, receiver) = mpsc::channel::<mail_sender::SenderMsg>();
 
+    // Mails accepted before a restart are sent first
+    for spooled in mail_sender::load_spooled_mails(&spool).expect("Failed to read spool directory") {
+        info!("Resuming spooled mail {}", spooled.id);
+        sender.send(mail_sender::SenderMsg::SendMail(spooled))
+            .expect("Failed to queue spooled mail");
+    }
+
+    {
+        let report_tracker = report_tracker.clone();
+        let sender = sender.clone();
+        let spool = spool.clone();
+        thread::spawn(move || run_report_task(report_agent, report_tracker, sender, spool));
+    }
+
+    {
+        let report_tracker = report_tracker.clone();
+        let sender = sender.clone();
+        let spool = spool.clone();
+        thread::spawn(move || run_expiry_task(report_tracker, sender, spool));
+    }
+
     thread::scope(|s| {
         s.spawn(|| {
-            run_sender_task(receiver, outbox_agent)
+            run_sender_task(receiver, outbox_agent, Path::new(aap_socket.as_str()), report_tracker, spool.clone())
         });
 
         run_smtp_server(SmtpConfig {
-            bind: "127.0.0.1:2525".to_owned()
-        }, sender.clone());
+            bind: "127.0.0.1:2525".to_owned(),
+            node_eid,
+            max_sessions: env::var("DDELIVERY_SMTP_MAX_SESSIONS")
+                .map(|it| it.parse().expect("Invalid maximum SMTP sessions count"))
+                .unwrap_or(DEFAULT_MAX_SESSIONS),
+            max_message_size: env::var("DDELIVERY_SMTP_MAX_MESSAGE_SIZE")
+                .map(|it| it.parse().expect("Invalid maximum message size"))
+                .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE),
+            tls: env::var("DDELIVERY_SMTP_TLS_CERTIFICATE").ok()
+                .zip(env::var("DDELIVERY_SMTP_TLS_KEY").ok())
+                .map(|(certificate, private_key)| TlsConfig {
+                    certificate: certificate.into(),
+                    private_key: private_key.into()
+                }),
+            require_tls: env::var("DDELIVERY_SMTP_REQUIRE_TLS")
+                .is_ok_and(|it| it == "1" || it == "true"),
+            auth: env::var("DDELIVERY_SMTP_CREDENTIALS").ok()
+                .map(|credentials| AuthConfig {
+                    credentials: credentials.into(),
+                    require_auth: env::var("DDELIVERY_SMTP_REQUIRE_AUTH")
+                        .is_ok_and(|it| it == "1" || it == "true"),
+                    sender_policy: match env::var("DDELIVERY_SMTP_SENDER_POLICY").as_deref() {
+                        Ok("any") => SenderPolicy::Any,
+                        _ => SenderPolicy::OwnAddresses
+                    }
+                }),
+            directory: env::var("DDELIVERY_SMTP_DIRECTORY").ok()
+                .map(|directory| directory.into()),
+            verify_privacy: env::var("DDELIVERY_SMTP_VERIFY_PRIVACY")
+                .is_ok_and(|it| it == "1" || it == "true"),
+            timeouts: {
+                let default = SmtpTimeouts::default();
+                SmtpTimeouts {
+                    initial: env_duration("DDELIVERY_SMTP_TIMEOUT_INITIAL", default.initial),
+                    mail: env_duration("DDELIVERY_SMTP_TIMEOUT_MAIL", default.mail),
+                    recipient: env_duration("DDELIVERY_SMTP_TIMEOUT_RCPT", default.recipient),
+                    data_initiation: env_duration("DDELIVERY_SMTP_TIMEOUT_DATA_INITIATION", default.data_initiation),
+                    data_block: env_duration("DDELIVERY_SMTP_TIMEOUT_DATA_BLOCK", default.data_block),
+                    data_termination: env_duration("DDELIVERY_SMTP_TIMEOUT_DATA_TERMINATION", default.data_termination)
+                }
+            }
+        }, sender.clone(), spool.clone());
 
         sender.send(mail_sender::SenderMsg::ShutdownTask)
             .expect("Failed to send shutdown message");
     });
 
-}
\ No newline at end of file
+}
+
+/// Read a duration in seconds from an environment variable
+fn env_duration(name: &str, default: Duration) -> Duration {
+    env::var(name)
+        .map(|it| Duration::from_secs(it.parse().unwrap_or_else(|_| panic!("Invalid duration in {name}"))))
+        .unwrap_or(default)
+}
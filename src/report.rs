@@ -0,0 +1,194 @@
+use std::{fmt::{self, Display}, process, sync::atomic::{AtomicU64, Ordering}, time::{SystemTime, UNIX_EPOCH}};
+
+use mail_parser::DateTime;
+
+static BOUNDARY_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Type of a diagnostic code, telling where it comes from (RFC 3464 section 2.3.6)
+#[derive(Debug, Clone, Copy)]
+pub enum DiagnosticType {
+    /// Reply of an SMTP or LMTP server
+    Smtp,
+    /// Error found by ddelivery itself
+    DDelivery
+}
+
+/// Reason given for the outcome of a delivery
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub code_type: DiagnosticType,
+    pub text: String
+}
+
+impl Display for Diagnostic {
+    /// Value of the Diagnostic-Code field, `type; text`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code_type {
+            DiagnosticType::Smtp => write!(f, "smtp; {}", self.text),
+            DiagnosticType::DDelivery => write!(f, "X-DDelivery; {}", self.text)
+        }
+    }
+}
+
+/// Delivery status of one recipient of a message
+pub struct RecipientStatus {
+    pub final_recipient: String,
+    /// Address type and original recipient given with ORCPT
+    pub original_recipient: Option<(String, String)>,
+    /// Value of the Action field : failed, delayed, delivered...
+    pub action: &'static str,
+    /// Enhanced status code of the delivery (RFC 3463)
+    pub status: String,
+    pub diagnostic: Option<Diagnostic>
+}
+
+/// Delivery status notification (RFC 3464) for the sender of a message
+pub struct StatusNotification<'a> {
+    /// Domain of this node, reporting the deliveries
+    pub reporting_domain: &'a str,
+    /// Address of the sender of the message
+    pub to: &'a str,
+    /// Outcome named in the subject, and text introducing the recipients
+    pub summary: (&'a str, &'a str),
+    pub envid: Option<&'a str>,
+    pub arrival: Option<SystemTime>,
+    pub recipients: Vec<RecipientStatus>,
+    /// Message being reported, of which only the header section is returned unless `return_full`
+    pub message: &'a [u8],
+    pub return_full: bool
+}
+
+impl StatusNotification<'_> {
+    /// Render the multipart/report message, header section included
+    pub fn render(&self) -> Vec<u8> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let counter = BOUNDARY_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let boundary = format!("{now}.{}.{counter}/{}", process::id(), self.reporting_domain);
+        let (subject, text) = self.summary;
+
+        let mut content = format!(
+            "Date: {}\r\n\
+            From: Mail Delivery System <postmaster@{domain}>\r\n\
+            To: <{}>\r\n\
+            Subject: Delivery Status Notification ({subject})\r\n\
+            Auto-Submitted: auto-replied\r\n\
+            MIME-Version: 1.0\r\n\
+            Content-Type: multipart/report; report-type=delivery-status; boundary=\"{boundary}\"\r\n\
+            \r\n\
+            --{boundary}\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            \r\n\
+            {text}\r\n\
+            \r\n",
+            DateTime::from_timestamp(now as i64).to_rfc822(),
+            self.to,
+            domain = self.reporting_domain
+        );
+
+        for recipient in &self.recipients {
+            match &recipient.diagnostic {
+                Some(diagnostic) => content.push_str(&format!("  {} : {}\r\n", recipient.final_recipient, diagnostic.text)),
+                None => content.push_str(&format!("  {}\r\n", recipient.final_recipient))
+            }
+        }
+
+        content.push_str(&format!(
+            "\r\n--{boundary}\r\n\
+            Content-Type: message/delivery-status\r\n\
+            \r\n\
+            Reporting-MTA: dns; {}\r\n",
+            self.reporting_domain
+        ));
+        if let Some(envid) = self.envid {
+            content.push_str(&format!("Original-Envelope-Id: {envid}\r\n"));
+        }
+        if let Some(arrival) = self.arrival {
+            let arrival = arrival.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            content.push_str(&format!("Arrival-Date: {}\r\n", DateTime::from_timestamp(arrival as i64).to_rfc822()));
+        }
+
+        for recipient in &self.recipients {
+            content.push_str(&format!("\r\nFinal-Recipient: rfc822; {}\r\n", recipient.final_recipient));
+            if let Some((address_type, address)) = &recipient.original_recipient {
+                content.push_str(&format!("Original-Recipient: {address_type}; {address}\r\n"));
+            }
+            content.push_str(&format!("Action: {}\r\nStatus: {}\r\n", recipient.action, recipient.status));
+            if let Some(diagnostic) = &recipient.diagnostic {
+                content.push_str(&format!("Diagnostic-Code: {diagnostic}\r\n"));
+            }
+        }
+
+        let (content_type, returned) = if self.return_full {
+            ("message/rfc822", self.message)
+        } else {
+            ("text/rfc822-headers", headers(self.message))
+        };
+        content.push_str(&format!("\r\n--{boundary}\r\nContent-Type: {content_type}\r\n\r\n"));
+
+        let mut content = content.into_bytes();
+        content.extend_from_slice(returned);
+        content.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+        content
+    }
+}
+
+/// Header section of a message, with the line ending of its last field
+pub fn headers(content: &[u8]) -> &[u8] {
+    let headers_end = content.windows(4)
+        .position(|it| it == b"\r\n\r\n")
+        .map(|it| it + 2)
+        .unwrap_or(content.len());
+    &content[..headers_end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notification(return_full: bool, diagnostic: Diagnostic) -> String {
+        let notification = StatusNotification {
+            reporting_domain: "node2",
+            to: "alice@node1",
+            summary: ("Failure", "Your message could not be delivered to the following recipients."),
+            envid: None,
+            arrival: None,
+            recipients: vec![RecipientStatus {
+                final_recipient: "bob@node2".to_owned(),
+                original_recipient: None,
+                action: "failed",
+                status: "5.1.1".to_owned(),
+                diagnostic: Some(diagnostic)
+            }],
+            message: b"Subject: Test\r\n\r\nHello\r\n",
+            return_full
+        };
+        String::from_utf8(notification.render()).unwrap()
+    }
+
+    #[test]
+    fn diagnostic_code_is_typed_after_its_origin() {
+        let content = notification(false, Diagnostic { code_type: DiagnosticType::Smtp, text: "550 5.1.1 No such user".to_owned() });
+        assert!(content.contains("Diagnostic-Code: smtp; 550 5.1.1 No such user\r\n"));
+        assert!(content.contains("  bob@node2 : 550 5.1.1 No such user\r\n"));
+
+        let content = notification(false, Diagnostic { code_type: DiagnosticType::DDelivery, text: "No mailbox for user bob".to_owned() });
+        assert!(content.contains("Diagnostic-Code: X-DDelivery; No mailbox for user bob\r\n"));
+    }
+
+    #[test]
+    fn only_headers_are_returned_without_full_return() {
+        let diagnostic = Diagnostic { code_type: DiagnosticType::DDelivery, text: "Failed".to_owned() };
+
+        let content = notification(false, diagnostic.clone());
+        assert!(content.contains("Content-Type: text/rfc822-headers\r\n\r\nSubject: Test\r\n\r\n--"));
+
+        let content = notification(true, diagnostic);
+        assert!(content.contains("Content-Type: message/rfc822\r\n\r\nSubject: Test\r\n\r\nHello\r\n\r\n--"));
+    }
+
+    #[test]
+    fn headers_end_with_the_last_field() {
+        assert_eq!(headers(b"Subject: Test\r\n\r\nHello\r\n"), b"Subject: Test\r\n");
+        assert_eq!(headers(b"Subject: Test\r\n"), b"Subject: Test\r\n");
+    }
+}
@@ -1,34 +1,284 @@
-use std::{io::{self, Read, Write}, iter::once, net::TcpStream, ops::Deref, string::FromUtf8Error};
+use std::{cell::RefCell, fmt::Display, io::{self, Read, Write}, net::{IpAddr, TcpStream}, process, rc::Rc, string::FromUtf8Error, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::{Duration, SystemTime, UNIX_EPOCH}};
 
-use log::error;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use log::{debug, error, info, warn};
+use mail_parser::DateTime;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
 use thiserror::Error;
 
+use crate::{auth::{AuthBackend, SenderPolicy, User}, directory::Directory, domain::{is_valid_local_part, node_name}, report};
+
+/// Maximum length of a command line including CRLF (RFC 5321 section 4.5.3.1.4), raised
+/// for the parameters of the advertised extensions, the longest being MAIL with SIZE
+/// (RFC 1870 section 3), DSN (RFC 3461 section 5), AUTH (RFC 4954 section 5) and
+/// SMTPUTF8 (RFC 6531 section 3.4) while RCPT only gains 500 octets with DSN
+const MAX_COMMAND_LINE_LENGTH: usize = 512 + 26 + 100 + 500 + 10;
+
+/// Maximum length of a SASL response line (RFC 4954 section 4)
+const MAX_AUTH_LINE_LENGTH: usize = 12288;
+
+/// Usage of supported commands returned by HELP
+const HELP_TOPICS: &[(&str, &str)] = &[
+    ("HELO", "HELO <domain> : identify the client"),
+    ("EHLO", "EHLO <domain> : identify the client and list service extensions"),
+    ("STARTTLS", "STARTTLS : start TLS negotiation"),
+    ("AUTH", "AUTH <mechanism> [initial-response] : authenticate the client"),
+    ("MAIL", "MAIL FROM:<address> [parameters] : start a mail transaction"),
+    ("RCPT", "RCPT TO:<address> [parameters] : add a recipient to the mail transaction"),
+    ("DATA", "DATA : send mail content ended by a line with a single dot"),
+    ("BDAT", "BDAT <size> [LAST] : send a chunk of mail content of size octets"),
+    ("RSET", "RSET : abort the mail transaction"),
+    ("VRFY", "VRFY <user> : verify a user of this node"),
+    ("EXPN", "EXPN <list> : expand a mailing list of this node"),
+    ("HELP", "HELP [command] : describe supported commands"),
+    ("NOOP", "NOOP : do nothing"),
+    ("QUIT", "QUIT : close the connection")
+];
+
+/// Time waited for the client at each step of a session (RFC 5321 section 4.5.3.2)
+#[derive(Debug, Clone, Copy)]
+pub struct SmtpTimeouts {
+    /// Sending the greeting and any reply, then waiting for the first command
+    pub initial: Duration,
+    /// Waiting for a MAIL command
+    pub mail: Duration,
+    /// Waiting for a RCPT, DATA or BDAT command during a mail transaction
+    pub recipient: Duration,
+    /// Waiting for mail content after the 354 reply
+    pub data_initiation: Duration,
+    /// Waiting for each block of mail content
+    pub data_block: Duration,
+    /// Submitting a received mail before replying to the final dot
+    pub data_termination: Duration
+}
+
+impl Default for SmtpTimeouts {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(5 * 60),
+            mail: Duration::from_secs(5 * 60),
+            recipient: Duration::from_secs(5 * 60),
+            data_initiation: Duration::from_secs(2 * 60),
+            data_block: Duration::from_secs(3 * 60),
+            data_termination: Duration::from_secs(10 * 60)
+        }
+    }
+}
+
+/// Counter making generated Message-ID unique within the process
+static MESSAGE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Settings shared by every SMTP session of the server
+pub struct SessionConfig {
+    pub domain: String,
+    /// Endpoint of this node, recorded in trace fields
+    pub node_eid: String,
+    pub tls: Option<Arc<ServerConfig>>,
+    pub require_tls: bool,
+    pub max_message_size: usize,
+    pub auth: Option<Arc<dyn AuthBackend>>,
+    pub require_auth: bool,
+    pub sender_policy: SenderPolicy,
+    pub directory: Option<Arc<dyn Directory>>,
+    /// Answer VRFY and EXPN with 252 instead of disclosing users
+    pub verify_privacy: bool,
+    pub timeouts: SmtpTimeouts
+}
+
+#[derive(Debug)]
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>)
+}
+
+impl Stream {
+    fn set_read_timeout(&self, timeout: Duration) -> Result<(), io::Error> {
+        match self {
+            Stream::Plain(stream) => stream.set_read_timeout(Some(timeout)),
+            Stream::Tls(stream) => stream.sock.set_read_timeout(Some(timeout))
+        }
+    }
+
+    fn shutdown(&mut self) -> Result<(), io::Error> {
+        let result = match self {
+            Stream::Plain(stream) => stream.shutdown(std::net::Shutdown::Both),
+            Stream::Tls(stream) => {
+                stream.conn.send_close_notify();
+                stream.flush()?;
+                stream.sock.shutdown(std::net::Shutdown::Both)
+            }
+        };
+
+        // The client may already have closed the connection
+        match result {
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+            result => result
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
 #[derive(Debug)]
+struct Connection {
+    stream: Stream,
+    /// Replies waiting to be sent, see [SessionStream]
+    replies: Vec<u8>
+}
+
+impl Connection {
+    fn flush_replies(&mut self) -> Result<(), io::Error> {
+        if !self.replies.is_empty() {
+            self.stream.write_all(&self.replies)?;
+            self.replies.clear();
+        }
+        self.stream.flush()
+    }
+}
+
+/// Connection to the client, shared between the session and its command reader
+/// so that it can be upgraded to TLS in place
+///
+/// Replies are buffered and only sent when the session is about to wait for the client,
+/// so that pipelined commands get their replies in a single write (RFC 2920).
+#[derive(Debug, Clone)]
+struct SessionStream(Rc<RefCell<Connection>>);
+
+impl SessionStream {
+    fn new(source: TcpStream) -> Self {
+        Self(Rc::new(RefCell::new(Connection { stream: Stream::Plain(source), replies: Vec::new() })))
+    }
+
+    fn is_tls(&self) -> bool {
+        matches!(self.0.borrow().stream, Stream::Tls(_))
+    }
+
+    fn set_read_timeout(&self, timeout: Duration) -> Result<(), io::Error> {
+        self.0.borrow().stream.set_read_timeout(timeout)
+    }
+
+    fn start_tls(&self, connection: ServerConnection) -> Result<(), io::Error> {
+        let mut inner = self.0.borrow_mut();
+
+        // Reply to STARTTLS must be sent in plain text
+        inner.flush_replies()?;
+
+        let Stream::Plain(source) = &inner.stream else {
+            return Err(io::Error::other("TLS already started"));
+        };
+
+        inner.stream = Stream::Tls(Box::new(StreamOwned::new(connection, source.try_clone()?)));
+        Ok(())
+    }
+
+    fn shutdown(&self) -> Result<(), io::Error> {
+        let mut inner = self.0.borrow_mut();
+        inner.flush_replies()?;
+        inner.stream.shutdown()
+    }
+}
+
+impl Read for SessionStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inner = self.0.borrow_mut();
+        inner.flush_replies()?;
+        inner.stream.read(buf)
+    }
+}
+
+impl Write for SessionStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().replies.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush_replies()
+    }
+}
+
 pub struct Session {
-    source: TcpStream
+    source: SessionStream,
+    config: Arc<SessionConfig>,
+    peer: Option<IpAddr>
 }
 
 impl Session {
-    pub fn new(mut source: TcpStream, domain: String) -> Result<Self, io::Error> {
+    pub fn new(mut source: TcpStream, config: Arc<SessionConfig>) -> Result<Self, io::Error> {
+        source.set_write_timeout(Some(config.timeouts.initial))?;
+
         if let Err(e) = source.write_all(
-            &ServerCommand::OpeningMessage(domain.clone()).into_bytes()) {
+            &ServerCommand::OpeningMessage(config.domain.clone()).into_bytes()) {
             return Err(e);
         }
 
-        Ok(Self { source })
+        let peer = source.peer_addr().ok().map(|it| it.ip());
+
+        Ok(Self { source: SessionStream::new(source), config, peer })
+    }
+
+    /// Refuse a connection when the server cannot take a new session
+    pub fn refuse(mut source: TcpStream, domain: String) -> Result<(), io::Error> {
+        source.write_all(&ServerCommand::ServiceNotAvailable(domain).into_bytes())?;
+        source.shutdown(std::net::Shutdown::Both)
     }
 
     fn recv_commands(&self) -> Result<CommandIter, io::Error> {
-        Ok(CommandIter { source: self.source.try_clone()?, buffer: Vec::new(), data: false })
+        Ok(CommandIter {
+            source: self.source.clone(),
+            buffer: Vec::new(),
+            data: false,
+            closed: false,
+            max_message_size: self.config.max_message_size,
+            data_block_timeout: self.config.timeouts.data_block
+        })
     }
 
     fn send_command(&mut self, command: ServerCommand) -> Result<(), io::Error> {
-        self.source.write_all(&command.into_bytes())?;
+        let reply = command.into_bytes();
+
+        if matches!(reply.first(), Some(b'4' | b'5')) {
+            debug!("Command refused : {}", String::from_utf8_lossy(&reply).trim_end());
+        }
+
+        self.source.write_all(&reply)?;
         Ok(())
     }
 
+    fn is_tls(&self) -> bool {
+        self.source.is_tls()
+    }
+
+    /// Authentication is only offered once the connection is encrypted, unless TLS is not configured
+    fn auth_available(&self) -> bool {
+        self.config.auth.is_some() && (self.config.tls.is_none() || self.is_tls())
+    }
+
     pub fn shutdown(&mut self) -> Result<(), io::Error> {
-        self.source.shutdown(std::net::Shutdown::Both)
+        self.source.shutdown()
     }
 
     pub fn into_mail_iter(self) -> Result<MailReceiver, io::Error> {
@@ -45,20 +295,27 @@ impl Drop for Session {
 }
 
 pub struct CommandIter {
-    source: TcpStream,
+    source: SessionStream,
     data: bool,
-    buffer: Vec<u8>
+    closed: bool,
+    buffer: Vec<u8>,
+    max_message_size: usize,
+    data_block_timeout: Duration
 }
 
-impl Iterator for CommandIter {
-    type Item = Result<ClientCommand, SmtpError>;
+impl CommandIter {
+    /// Read the next line sent by the client, including its CRLF ending
+    ///
+    /// Lines longer than `max_length` are discarded without being buffered entirely.
+    fn read_line(&mut self, max_length: usize) -> Option<Result<Vec<u8>, SmtpError>> {
+        if self.closed {
+            return None;
+        }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut ended = false;
         let mut read_buffer = [0_u8; 2048];
-        let mut buffered_data: Vec<u8> = Vec::new();
+        let mut too_long = false;
 
-        while !ended {
+        loop {
 
             let buffered_line = { // Buffered line with CRLF ending
                 let mut cr = false;
@@ -79,50 +336,142 @@ impl Iterator for CommandIter {
                         self.buffer.drain(0..line_position+1).collect::<Vec<_>>())
             };
 
-            if let Some(mut buffered_line) = buffered_line {
+            if let Some(buffered_line) = buffered_line {
+                if too_long || buffered_line.len() > max_length {
+                    return Some(Err(SmtpError::LineTooLong));
+                }
+                return Some(Ok(buffered_line));
+            }
 
-                if self.data {
-                    if buffered_line == b".\r\n" {
-                        self.data = false;
-                        return Some(Ok(ClientCommand::MailInput(buffered_data)));
-                    } else {
-                        if buffered_line.starts_with(b".") {
-                            buffered_line.remove(0);
-                        }
-                        buffered_data.append(&mut buffered_line);
+            if self.buffer.len() > max_length {
+                // Keep a trailing CR as it may be the start of the line ending
+                let kept = if self.buffer.ends_with(b"\r") { 1 } else { 0 };
+                self.buffer.drain(..self.buffer.len()-kept);
+                too_long = true;
+            }
+
+            match self.source.read(&mut read_buffer) {
+                Err(e) if is_timeout(&e) => {
+                    self.closed = true;
+                    return Some(Err(SmtpError::Timeout))
+                },
+                Err(e) => {
+                    self.closed = true;
+                    return Some(Err(SmtpError::Io(e)))
+                },
+                Ok(0) => return None,
+                Ok(byte_red) => self.buffer.extend_from_slice(&read_buffer[0..byte_red])
+            }
+        }
+    }
+}
+
+impl CommandIter {
+    /// Read exactly `size` octets of a BDAT chunk, only keeping them if `keep` is set
+    fn read_chunk(&mut self, size: usize, keep: bool) -> Result<Vec<u8>, io::Error> {
+        let mut chunk = Vec::new();
+        let mut remaining = size;
+        let mut read_buffer = [0_u8; 2048];
+
+        self.source.set_read_timeout(self.data_block_timeout)?;
+
+        loop {
+            let available = remaining.min(self.buffer.len());
+            let data = self.buffer.drain(..available);
+            if keep {
+                chunk.extend(data);
+            } else {
+                drop(data);
+            }
+            remaining -= available;
+
+            if remaining == 0 {
+                return Ok(chunk);
+            }
+
+            match self.source.read(&mut read_buffer) {
+                Err(e) => {
+                    self.closed = true;
+                    return Err(e)
+                },
+                Ok(0) => {
+                    self.closed = true;
+                    return Err(io::ErrorKind::UnexpectedEof.into())
+                },
+                Ok(byte_red) => self.buffer.extend_from_slice(&read_buffer[0..byte_red])
+            }
+        }
+    }
+}
+
+impl Iterator for CommandIter {
+    type Item = Result<ClientCommand, SmtpError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buffered_data: Vec<u8> = Vec::new();
+        let mut too_large = false;
+        let mut data_started = false;
+
+        loop {
+            let max_length = if self.data { self.max_message_size } else { MAX_COMMAND_LINE_LENGTH };
+
+            let mut buffered_line = match self.read_line(max_length)? {
+                Ok(line) => line,
+                Err(SmtpError::LineTooLong) if self.data => {
+                    too_large = true;
+                    continue;
+                },
+                Err(e) => return Some(Err(e))
+            };
+
+            if self.data {
+                if !data_started {
+                    // Following blocks are waited for with the data block timeout
+                    data_started = true;
+                    if let Err(e) = self.source.set_read_timeout(self.data_block_timeout) {
+                        return Some(Err(SmtpError::Io(e)));
                     }
-                } else {
-                    let command = match ClientCommand::from_bytes(&buffered_line) {
-                        Ok(it) => it,
-                        Err(e) => {
-                            return Some(Err(SmtpError::Command(e)));
-                        }
-                    };
+                }
 
-                    if matches!(command, ClientCommand::Data) {
-                        self.data = true;
+                if buffered_line == b".\r\n" {
+                    self.data = false;
+                    if too_large {
+                        return Some(Err(SmtpError::MessageTooLarge));
+                    }
+                    return Some(Ok(ClientCommand::MailInput(buffered_data)));
+                } else if !too_large {
+                    if buffered_line.starts_with(b".") {
+                        buffered_line.remove(0);
                     }
 
-                    return Some(Ok(command));
+                    if buffered_data.len() + buffered_line.len() > self.max_message_size {
+                        // Keep reading until the end of data but stop storing it
+                        too_large = true;
+                        buffered_data = Vec::new();
+                    } else {
+                        buffered_data.append(&mut buffered_line);
+                    }
                 }
-
             } else {
-                let result = self.source.read(&mut read_buffer);
+                let command = match ClientCommand::from_bytes(&buffered_line) {
+                    Ok(it) => it,
+                    Err(e) => {
+                        return Some(Err(SmtpError::Command(e)));
+                    }
+                };
 
-                match result {
-                    Err(e) => return Some(Err(SmtpError::Io(e))),
-                    Ok(byte_red) => {
-                        if byte_red > 0 {
-                            self.buffer.extend_from_slice(&mut read_buffer[0..byte_red])
-                        } else {
-                            ended = true
-                        }
-                    },
+                // A chunk larger than any message is refused before its data, which
+                // could then not be told from commands, so the connection is closed
+                if let ClientCommand::Bdat { size, .. } = command {
+                    if size > self.max_message_size {
+                        self.closed = true;
+                        return Some(Err(SmtpError::ChunkTooLarge));
+                    }
                 }
+
+                return Some(Ok(command));
             }
         }
-
-        None
     }
 }
 
@@ -132,22 +481,229 @@ pub enum SmtpError {
     Io(#[from] io::Error),
     #[error("Command parsing error : {0}")]
     Command(#[from] ClientCommandParseError),
+    #[error("Line too long")]
+    LineTooLong,
+    #[error("Message exceeds maximum size")]
+    MessageTooLarge,
+    #[error("BDAT chunk exceeds maximum message size")]
+    ChunkTooLarge,
+    #[error("Timeout waiting for the client")]
+    Timeout
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
 }
 
 #[allow(dead_code)]
 #[derive(Debug)]
 pub enum ClientCommand {
     Hello(String),
-    Mail(EmailAddress),
-    Recipient(EmailAddress),
+    ExtendedHello(String),
+    Mail(EmailAddress, MailParameters),
+    Recipient(EmailAddress, RcptParameters),
     Data,
     MailInput(Vec<u8>),
+    Bdat {
+        size: usize,
+        last: bool
+    },
     Quit,
     Reset,
     Verify(String),
     Expand(String),
     Help(Option<String>),
     Noop(Option<String>),
+    StartTls,
+    Auth(AuthMechanism, Option<String>),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AuthMechanism {
+    Plain,
+    Login
+}
+
+/// ESMTP parameters of a MAIL command
+#[derive(Debug, Clone, Default)]
+pub struct MailParameters {
+    pub body: Option<BodyType>,
+    pub size: Option<usize>,
+    pub smtputf8: bool,
+    pub ret: Option<DsnReturn>,
+    pub envid: Option<String>,
+    pub auth: Option<String>
+}
+
+/// ESMTP parameters of a RCPT command
+#[derive(Debug, Clone, Default)]
+pub struct RcptParameters {
+    pub notify: Option<DsnNotify>,
+    pub orcpt: Option<OriginalRecipient>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyType {
+    SevenBit,
+    EightBitMime,
+    BinaryMime
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DsnReturn {
+    Full,
+    Headers
+}
+
+/// Conditions requiring a delivery status notification, all false for NOTIFY=NEVER
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DsnNotify {
+    pub success: bool,
+    pub failure: bool,
+    pub delay: bool
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OriginalRecipient {
+    pub address_type: String,
+    pub address: String
+}
+
+/// Split `FROM:<path> PARAM=value ...` into the path and its parameters
+fn split_path_parameters<'a>(params: &'a [u8], prefix: &[u8]) -> Result<(Vec<u8>, Vec<(String, Option<&'a str>)>), ClientCommandParseError> {
+    if params.len() < prefix.len() || !params[..prefix.len()].eq_ignore_ascii_case(prefix) {
+        return Err(ClientCommandParseError::SyntaxInvalid);
+    }
+
+    let params = &params[prefix.len()..];
+    let params = &params[params.iter().take_while(|it| **it == b' ').count()..];
+
+    if !params.starts_with(b"<") {
+        return Err(ClientCommandParseError::SyntaxInvalid);
+    }
+
+    let Some(path_end) = params.iter().position(|it| *it == b'>') else {
+        return Err(ClientCommandParseError::SyntaxInvalid);
+    };
+
+    let parameters = params[path_end+1..].split(|it| *it == b' ')
+        .filter(|it| !it.is_empty())
+        .map(|param| {
+            let param = std::str::from_utf8(param)
+                .map_err(|_| ClientCommandParseError::InvalidParameter(String::from_utf8_lossy(param).into_owned()))?;
+
+            Ok(match param.split_once('=') {
+                Some((keyword, value)) => (keyword.to_ascii_uppercase(), Some(value)),
+                None => (param.to_ascii_uppercase(), None)
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((params[..=path_end].to_vec(), parameters))
+}
+
+/// Decode an xtext encoded parameter value (RFC 3461 section 4)
+fn decode_xtext(value: &str) -> Option<String> {
+    let mut decoded = Vec::new();
+    let mut bytes = value.bytes();
+
+    while let Some(byte) = bytes.next() {
+        if byte == b'+' {
+            let hex = [bytes.next()?, bytes.next()?];
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+impl MailParameters {
+    fn from_parameters(params: Vec<(String, Option<&str>)>) -> Result<Self, ClientCommandParseError> {
+        let mut result = Self::default();
+
+        for (keyword, value) in params {
+            let invalid = || ClientCommandParseError::InvalidParameter(keyword.clone());
+
+            match (keyword.as_str(), value) {
+                ("BODY", Some(value)) if result.body.is_none() => {
+                    result.body = Some(match value.to_ascii_uppercase().as_str() {
+                        "7BIT" => BodyType::SevenBit,
+                        "8BITMIME" => BodyType::EightBitMime,
+                        "BINARYMIME" => BodyType::BinaryMime,
+                        _ => return Err(invalid())
+                    });
+                },
+                ("SIZE", Some(value)) if result.size.is_none() => {
+                    result.size = Some(value.parse().map_err(|_| invalid())?);
+                },
+                ("SMTPUTF8", None) if !result.smtputf8 => {
+                    result.smtputf8 = true;
+                },
+                ("RET", Some(value)) if result.ret.is_none() => {
+                    result.ret = Some(match value.to_ascii_uppercase().as_str() {
+                        "FULL" => DsnReturn::Full,
+                        "HDRS" => DsnReturn::Headers,
+                        _ => return Err(invalid())
+                    });
+                },
+                ("ENVID", Some(value)) if result.envid.is_none() => {
+                    result.envid = Some(decode_xtext(value).ok_or_else(invalid)?);
+                },
+                ("AUTH", Some(value)) if result.auth.is_none() => {
+                    result.auth = Some(decode_xtext(value).ok_or_else(invalid)?);
+                },
+                ("BODY" | "SIZE" | "SMTPUTF8" | "RET" | "ENVID" | "AUTH", _) => return Err(invalid()),
+                _ => return Err(ClientCommandParseError::UnknownParameter(keyword))
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+impl RcptParameters {
+    fn from_parameters(params: Vec<(String, Option<&str>)>) -> Result<Self, ClientCommandParseError> {
+        let mut result = Self::default();
+
+        for (keyword, value) in params {
+            let invalid = || ClientCommandParseError::InvalidParameter(keyword.clone());
+
+            match (keyword.as_str(), value) {
+                ("NOTIFY", Some(value)) if result.notify.is_none() => {
+                    let mut notify = DsnNotify::default();
+
+                    if !value.eq_ignore_ascii_case("NEVER") {
+                        for condition in value.split(',') {
+                            match condition.to_ascii_uppercase().as_str() {
+                                "SUCCESS" => notify.success = true,
+                                "FAILURE" => notify.failure = true,
+                                "DELAY" => notify.delay = true,
+                                _ => return Err(invalid())
+                            }
+                        }
+                    }
+
+                    result.notify = Some(notify);
+                },
+                ("ORCPT", Some(value)) if result.orcpt.is_none() => {
+                    let Some((address_type, address)) = value.split_once(';') else {
+                        return Err(invalid());
+                    };
+
+                    result.orcpt = Some(OriginalRecipient {
+                        address_type: address_type.to_owned(),
+                        address: decode_xtext(address).ok_or_else(invalid)?
+                    });
+                },
+                ("NOTIFY" | "ORCPT", _) => return Err(invalid()),
+                _ => return Err(ClientCommandParseError::UnknownParameter(keyword))
+            }
+        }
+
+        Ok(result)
+    }
 }
 
 impl ClientCommand {
@@ -175,7 +731,7 @@ impl ClientCommand {
 
         match command_str.as_str() {
 
-            "EHLO" => {
+            "HELO" => {
                 let Some(domain) = options.get(1) else {
                     return Err(ClientCommandParseError::MissingDomain);
                 };
@@ -186,27 +742,30 @@ impl ClientCommand {
                 }
             }
 
+            "EHLO" => {
+                let Some(domain) = options.get(1) else {
+                    return Err(ClientCommandParseError::MissingDomain);
+                };
+
+                match String::from_utf8(domain.to_vec()) {
+                    Ok(domain) => Ok(ClientCommand::ExtendedHello(domain)),
+                    Err(e) => Err(ClientCommandParseError::InvalidCharacter(e))
+                }
+            }
+
             "MAIL" => {
                 let Some(params) = options.get(1) else {
                     return Err(ClientCommandParseError::MissingDomain);
                 };
 
-                if ! params.starts_with(b"FROM:") {
-                    return Err(ClientCommandParseError::SyntaxInvalid);
-                }
+                let (path, params) = split_path_parameters(params, b"FROM:")?;
 
-                match EmailAddress::from_bytes(
-                    params[5..].into_iter()
-                        .copied()
-                        .take_while(|it| *it != b'>')
-                        .chain(once(b'>'))
-                        .collect::<Vec<_>>()
-                    ) {
-                        Ok(from) => {
-                            Ok(ClientCommand::Mail(from))
-                        },
-                        Err(e) => Err(ClientCommandParseError::InvalidFrom(e))
-                }
+                let from = match EmailAddress::from_bytes(path) {
+                    Ok(EmailAddress::Postmaster) => Err(BadAddressError::PostmasterSender),
+                    result => result
+                }.map_err(ClientCommandParseError::InvalidFrom)?;
+
+                Ok(ClientCommand::Mail(from, MailParameters::from_parameters(params)?))
             }
 
             "RCPT" => {
@@ -214,22 +773,44 @@ impl ClientCommand {
                     return Err(ClientCommandParseError::MissingDomain);
                 };
 
-                let params = params.split(|it| *it == b' ').collect::<Vec<_>>();
+                let (path, params) = split_path_parameters(params, b"TO:")?;
 
-                let Some(recipient) = params.get(0).and_then(|it| if it.starts_with(b"TO:") { Some(it) } else { None }) else {
-                    return Err(ClientCommandParseError::SyntaxInvalid);
-                };
+                let recipient = match EmailAddress::from_bytes(path) {
+                    Ok(EmailAddress::Null) => Err(BadAddressError::NullRecipient),
+                    result => result
+                }.map_err(ClientCommandParseError::InvalidRecipient)?;
 
-                match EmailAddress::from_bytes(recipient[3..].to_vec()) {
-                    Ok(recipient) => Ok(ClientCommand::Recipient(recipient)),
-                    Err(e) => Err(ClientCommandParseError::InvalidRecipient(e))
-                }
+                Ok(ClientCommand::Recipient(recipient, RcptParameters::from_parameters(params)?))
             }
 
             "DATA" => {
                 Ok(Self::Data)
             }
 
+            "BDAT" => {
+                let Some(params) = options.get(1) else {
+                    return Err(ClientCommandParseError::MissingParameter);
+                };
+
+                let params = params.split(|it| *it == b' ')
+                    .filter(|it| !it.is_empty())
+                    .collect::<Vec<_>>();
+
+                let Some(size) = params.first()
+                    .filter(|it| it.iter().all(u8::is_ascii_digit))
+                    .and_then(|it| std::str::from_utf8(it).ok())
+                    // Sizes beyond the address space are refused as too large, not as invalid
+                    .map(|it| it.parse().unwrap_or(usize::MAX)) else {
+                    return Err(ClientCommandParseError::SyntaxInvalid);
+                };
+
+                match &params[1..] {
+                    [] => Ok(Self::Bdat { size, last: false }),
+                    [last] if last.eq_ignore_ascii_case(b"LAST") => Ok(Self::Bdat { size, last: true }),
+                    _ => Err(ClientCommandParseError::SyntaxInvalid)
+                }
+            }
+
             "QUIT" => {
                 Ok(Self::Quit)
             }
@@ -272,6 +853,36 @@ impl ClientCommand {
                 return Ok(ClientCommand::Help(None))
             }
 
+            "STARTTLS" => {
+                if options.get(1).is_some() {
+                    return Err(ClientCommandParseError::SyntaxInvalid);
+                }
+
+                Ok(Self::StartTls)
+            }
+
+            "AUTH" => {
+                let Some(params) = options.get(1) else {
+                    return Err(ClientCommandParseError::MissingParameter);
+                };
+
+                let params = match String::from_utf8(params.to_vec()) {
+                    Ok(params) => params,
+                    Err(e) => return Err(ClientCommandParseError::InvalidCharacter(e))
+                };
+
+                let (mechanism, initial_response) = match params.split_once(' ') {
+                    Some((mechanism, initial_response)) => (mechanism, Some(initial_response.to_owned())),
+                    None => (params.as_str(), None)
+                };
+
+                match mechanism.to_ascii_uppercase().as_str() {
+                    "PLAIN" => Ok(ClientCommand::Auth(AuthMechanism::Plain, initial_response)),
+                    "LOGIN" => Ok(ClientCommand::Auth(AuthMechanism::Login, initial_response)),
+                    _ => Err(ClientCommandParseError::UnknownAuthMechanism(mechanism.to_owned()))
+                }
+            }
+
             "NOOP" => {
                 if let Some(param_str) = options.get(1) {
                     return match String::from_utf8(param_str.to_vec()) {
@@ -307,15 +918,23 @@ pub enum ClientCommandParseError {
     InvalidCommand(String),
     #[error("Required parameter is missing")]
     MissingParameter,
-    #[error("Invalid recipient")]
+    #[error("Invalid recipient : {0}")]
     InvalidRecipient(BadAddressError),
-    #[error("Invalid from")]
-    InvalidFrom(BadAddressError)
+    #[error("Invalid from : {0}")]
+    InvalidFrom(BadAddressError),
+    #[error("Unknown authentication mechanism {0}")]
+    UnknownAuthMechanism(String),
+    #[error("Invalid parameter {0}")]
+    InvalidParameter(String),
+    #[error("Unknown parameter {0}")]
+    UnknownParameter(String)
 }
 
 #[derive(Debug)]
 pub enum ServerCommand {
     OpeningMessage(String),
+    ServiceNotAvailable(String),
+    Timeout(String),
     HelloOk {
         domain: String,
         greet: Option<String>,
@@ -327,92 +946,482 @@ pub enum ServerCommand {
     ResetOk,
     StartMailInput,
     MailOk,
+    ChunkOk(usize),
+    LocalError(String),
+    InsufficientStorage,
+    ReadyToStartTls,
+    TlsNotAvailable,
+    TlsRequired,
+    AuthContinue(String),
+    AuthOk,
+    AuthRequired,
+    AuthInvalid,
+    AuthAborted,
+    AuthMechanismUnknown(String),
+    EncryptionRequired,
+    SenderNotAllowed,
+    LineTooLong,
+    MessageTooLarge,
+    ParameterNotRecognized(String),
+    InvalidArguments(String),
+    BadSenderAddress(String),
+    BadRecipientAddress(String),
+    Utf8AddressNotAllowed,
     ClosingConnection,
-    SyntaxError,
-    CommandUnrecognized,
+    SyntaxError(String),
+    CommandUnrecognized(String),
     CommandNotImplemented,
-    BadSequenceOfCommand(String)
+    BadSequenceOfCommand(String),
+    UserVerified(String),
+    UserAmbiguous(Vec<String>),
+    UserUnknown(String),
+    ListExpanded(Vec<String>),
+    ListUnknown(String),
+    CannotVerify,
+    HelpOk(Vec<String>),
+    HelpTopicUnknown(String)
 }
 
 impl ServerCommand {
-    pub fn into_bytes(self) -> Vec<u8> {
+    /// Reply code, enhanced status code (RFC 3463) and text of the reply, one line per text line
+    fn reply(self) -> (u16, Option<&'static str>, String) {
         match self {
+            // Greeting and EHLO replies do not carry enhanced status codes (RFC 2034 section 3)
+            ServerCommand::OpeningMessage(domain) =>
+                (220, None, format!("{domain} Service ready")),
 
-            ServerCommand::OpeningMessage(domain) => 
-                format!("220 {domain} Service ready\r\n").into_bytes(),
+            ServerCommand::ServiceNotAvailable(domain) =>
+                (421, Some("4.3.2"), format!("{domain} Service not available, closing transmission channel")),
 
-            ServerCommand::HelloOk { domain, greet, mut extensions } => {
-                    let mut lines = vec![
-                        domain
-                    ];
+            ServerCommand::Timeout(domain) =>
+                (421, Some("4.4.2"), format!("{domain} Timeout waiting for client, closing transmission channel")),
 
-                    if let Some(greet) = greet.as_ref() {
-                        lines[0] += &format!(" {greet}");
+            ServerCommand::HelloOk { domain, greet, mut extensions } => {
+                let mut lines = vec![
+                    match greet {
+                        Some(greet) => format!("{domain} {greet}"),
+                        None => domain
                     }
+                ];
+                lines.append(&mut extensions);
 
-                    lines.append(&mut extensions);
-
-                    let size = lines.len();
-                    format!("{}\r\n", lines.iter()
-                            .enumerate()
-                            .map(|(i, it)| if i < size-1 {
-                                    format!("250-{it}")
-                                } else {
-                                    format!("250 {it}")
-                                }).collect::<Vec<_>>()
-                            .join("\r\n")
-                        ).into_bytes()
-                },
+                (250, None, lines.join("\n"))
+            },
 
-            ServerCommand::SenderOk => 
-                format!("250 Sender Ok\r\n").into_bytes(),
+            ServerCommand::SenderOk =>
+                (250, Some("2.1.0"), "Sender Ok".to_owned()),
 
-            ServerCommand::RecipientOk => 
-                format!("250 Recipient Ok\r\n").into_bytes(),
+            ServerCommand::RecipientOk =>
+                (250, Some("2.1.5"), "Recipient Ok".to_owned()),
 
-            ServerCommand::StartMailInput => 
-                format!("354  Start mail input; end with <CRLF>.<CRLF>\r\n").into_bytes(),
+            ServerCommand::StartMailInput =>
+                (354, None, "Start mail input; end with <CRLF>.<CRLF>".to_owned()),
 
-            ServerCommand::MailOk => 
-                format!("250 Mail Ok\r\n").into_bytes(),
+            ServerCommand::MailOk =>
+                (250, Some("2.0.0"), "Mail Ok".to_owned()),
 
-            ServerCommand::ClosingConnection => 
-                format!("221 Closing connection\r\n").into_bytes(),
+            ServerCommand::ChunkOk(size) =>
+                (250, Some("2.0.0"), format!("{size} octets received")),
 
-            ServerCommand::SyntaxError => 
-                format!("501 Syntax error\r\n").into_bytes(),
+            ServerCommand::LocalError(reason) =>
+                (451, Some("4.3.0"), format!("Requested action aborted: {reason}")),
 
-            ServerCommand::CommandNotImplemented => 
-                format!("502 Not implemented\r\n").into_bytes(),
+            ServerCommand::InsufficientStorage =>
+                (452, Some("4.3.1"), "Insufficient system storage".to_owned()),
 
-            ServerCommand::CommandUnrecognized => 
-                format!("500 Command unrecognized\r\n").into_bytes(),
+            ServerCommand::ReadyToStartTls =>
+                (220, Some("2.0.0"), "Ready to start TLS".to_owned()),
 
-            ServerCommand::BadSequenceOfCommand(text) => 
-                format!("503 Bad sequence of command. {text}\r\n").into_bytes(),
+            ServerCommand::TlsNotAvailable =>
+                (454, Some("4.7.0"), "TLS not available due to temporary reason".to_owned()),
 
-            ServerCommand::NoopOk => 
-                format!("250 OK\r\n").into_bytes(),
+            ServerCommand::TlsRequired =>
+                (530, Some("5.7.0"), "Must issue a STARTTLS command first".to_owned()),
 
-            ServerCommand::ResetOk => 
-                format!("250 OK\r\n").into_bytes(),
-        }
-    }
-}
+            ServerCommand::AuthContinue(challenge) =>
+                (334, None, BASE64_STANDARD.encode(challenge)),
 
-pub struct MailReceiver {
-    session: Session,
-    commands: CommandIter
-}
+            ServerCommand::AuthOk =>
+                (235, Some("2.7.0"), "Authentication successful".to_owned()),
 
-impl MailReceiver {
-    pub fn new(smtp_session: Session) -> Result<Self, io::Error> {
+            ServerCommand::AuthRequired =>
+                (530, Some("5.7.0"), "Authentication required".to_owned()),
+
+            ServerCommand::AuthInvalid =>
+                (535, Some("5.7.8"), "Authentication credentials invalid".to_owned()),
+
+            ServerCommand::AuthAborted =>
+                (501, Some("5.7.0"), "Authentication aborted".to_owned()),
+
+            ServerCommand::AuthMechanismUnknown(mechanism) =>
+                (504, Some("5.5.4"), format!("Unrecognized authentication type {mechanism}")),
+
+            ServerCommand::EncryptionRequired =>
+                (538, Some("5.7.11"), "Encryption required for requested authentication mechanism".to_owned()),
+
+            ServerCommand::SenderNotAllowed =>
+                (553, Some("5.7.1"), "Sender address not allowed for authenticated user".to_owned()),
+
+            ServerCommand::LineTooLong =>
+                (500, Some("5.5.2"), "Line too long".to_owned()),
+
+            ServerCommand::MessageTooLarge =>
+                (552, Some("5.3.4"), "Message size exceeds fixed maximum message size".to_owned()),
+
+            ServerCommand::ParameterNotRecognized(reason) =>
+                (555, Some("5.5.4"), format!("MAIL FROM/RCPT TO parameters not recognized or not implemented: {reason}")),
+
+            ServerCommand::InvalidArguments(reason) =>
+                (501, Some("5.5.4"), format!("Invalid command arguments: {reason}")),
+
+            ServerCommand::BadSenderAddress(reason) =>
+                (501, Some("5.1.7"), format!("Bad sender address syntax: {reason}")),
+
+            ServerCommand::BadRecipientAddress(reason) =>
+                (501, Some("5.1.3"), format!("Bad recipient address syntax: {reason}")),
+
+            ServerCommand::Utf8AddressNotAllowed =>
+                (553, Some("5.6.7"), "Internationalized address requires SMTPUTF8".to_owned()),
+
+            ServerCommand::ClosingConnection =>
+                (221, Some("2.0.0"), "Closing connection".to_owned()),
+
+            ServerCommand::SyntaxError(reason) =>
+                (501, Some("5.5.2"), format!("Syntax error: {reason}")),
+
+            ServerCommand::CommandNotImplemented =>
+                (502, Some("5.5.1"), "Not implemented".to_owned()),
+
+            ServerCommand::CommandUnrecognized(reason) =>
+                (500, Some("5.5.2"), format!("Command unrecognized: {reason}")),
+
+            ServerCommand::BadSequenceOfCommand(text) =>
+                (503, Some("5.5.1"), format!("Bad sequence of command. {text}")),
+
+            ServerCommand::UserVerified(user) =>
+                (250, Some("2.1.5"), user),
+
+            ServerCommand::UserAmbiguous(users) =>
+                (553, Some("5.1.4"), format!("User ambiguous, possibilities are\n{}", users.join("\n"))),
+
+            ServerCommand::UserUnknown(query) =>
+                (550, Some("5.1.1"), format!("User unknown: {query}")),
+
+            ServerCommand::ListExpanded(members) if members.is_empty() =>
+                (250, Some("2.1.5"), "Mailing list is empty".to_owned()),
+
+            ServerCommand::ListExpanded(members) =>
+                (250, Some("2.1.5"), members.join("\n")),
+
+            ServerCommand::ListUnknown(list) =>
+                (550, Some("5.1.1"), format!("Mailing list unknown: {list}")),
+
+            ServerCommand::CannotVerify =>
+                (252, Some("2.0.0"), "Cannot VRFY user, but will accept message and attempt delivery".to_owned()),
+
+            ServerCommand::HelpOk(lines) =>
+                (214, Some("2.0.0"), lines.join("\n")),
+
+            ServerCommand::HelpTopicUnknown(topic) =>
+                (504, Some("5.5.1"), format!("HELP topic unknown: {topic}")),
+
+            ServerCommand::NoopOk =>
+                (250, Some("2.0.0"), "OK".to_owned()),
+
+            ServerCommand::ResetOk =>
+                (250, Some("2.0.0"), "OK".to_owned()),
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        let (code, enhanced_code, text) = self.reply();
+
+        let mut lines = text.lines().collect::<Vec<_>>();
+        if lines.is_empty() {
+            lines.push("");
+        }
+        let last = lines.len() - 1;
+
+        let mut reply = String::new();
+        for (i, line) in lines.iter().enumerate() {
+            let separator = if i < last { '-' } else { ' ' };
+            match enhanced_code {
+                Some(enhanced_code) => reply += &format!("{code}{separator}{enhanced_code} {line}\r\n"),
+                None => reply += &format!("{code}{separator}{line}\r\n")
+            }
+        }
+
+        reply.into_bytes()
+    }
+}
+
+/// Progress of the SMTP dialog (RFC 5321 section 4.1.4)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SessionState {
+    /// No HELO or EHLO received yet
+    Connected,
+    /// Client greeted, no mail transaction
+    Greeted,
+    /// MAIL accepted, waiting for recipients
+    Mail,
+    /// At least one recipient accepted
+    Recipient,
+    /// Receiving mail content
+    Data,
+    /// Receiving mail content in BDAT chunks
+    Chunking
+}
+
+pub struct MailReceiver {
+    session: Session,
+    commands: CommandIter,
+    pending_mail: bool,
+    user: Option<User>,
+    state: SessionState,
+    client_domain: Option<String>,
+    extended: bool
+}
+
+impl MailReceiver {
+    /// Time to wait for the next command in the current state
+    fn command_timeout(&self) -> Duration {
+        let timeouts = &self.session.config.timeouts;
+        match self.state {
+            SessionState::Connected => timeouts.initial,
+            SessionState::Greeted => timeouts.mail,
+            SessionState::Mail | SessionState::Recipient | SessionState::Chunking => timeouts.recipient,
+            SessionState::Data => timeouts.data_initiation
+        }
+    }
+
+    /// Tell the client it was too slow and end the session
+    fn close_on_timeout(&mut self) -> Option<Result<Mail, io::Error>> {
+        info!("Closing SMTP session on client timeout");
+
+        let domain = self.session.config.domain.clone();
+        if let Err(e) = self.session.send_command(ServerCommand::Timeout(domain)) {
+            return Some(Err(e))
+        }
+        if let Err(e) = self.session.shutdown() {
+            return Some(Err(e))
+        }
+        None
+    }
+
+    /// Prepend a Received field to a mail (RFC 5321 section 4.4) and add the
+    /// Message-ID and Date fields if missing (RFC 6409 section 8)
+    fn trace(&self, mail: &mut Mail) {
+        let config = &self.session.config;
+        let node_name = &config.node_eid[6..config.node_eid.len()-1];
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let date = DateTime::from_timestamp(now.as_secs() as i64).to_rfc822();
+
+        let peer = match self.session.peer {
+            Some(IpAddr::V4(ip)) => format!("[{ip}]"),
+            Some(IpAddr::V6(ip)) => format!("[IPv6:{ip}]"),
+            None => "unknown".to_owned()
+        };
+
+        // Protocol types of RFC 3848 and RFC 6531
+        let protocol = format!("{}{}{}",
+            if mail.parameters.smtputf8 { "UTF8SMTP" } else if self.extended { "ESMTP" } else { "SMTP" },
+            if self.session.is_tls() { "S" } else { "" },
+            if self.user.is_some() { "A" } else { "" }
+        );
+
+        let recipient = match &mail.receipients[..] {
+            [recipient] => format!("\r\n\tfor {}", recipient.address),
+            _ => String::new()
+        };
+
+        let mut fields = format!("Received: from {} ({peer})\r\n\tby {node_name} ({}) with {protocol}{recipient};\r\n\t{date}\r\n",
+            self.client_domain.as_deref().unwrap_or("unknown"),
+            config.node_eid
+        );
+
+        let headers = report::headers(&mail.content);
+
+        if !has_header(headers, "Message-ID") {
+            let counter = MESSAGE_COUNTER.fetch_add(1, Ordering::Relaxed);
+            fields.push_str(&format!("Message-ID: <{}.{}.{counter}@{node_name}>\r\n", now.as_nanos(), process::id()));
+        }
+
+        if !has_header(headers, "Date") {
+            fields.push_str(&format!("Date: {date}\r\n"));
+        }
+
+        mail.content.splice(0..0, fields.into_bytes());
+    }
+
+    /// Service extensions advertised in reply to EHLO
+    fn extensions(&self) -> Vec<String> {
+        let mut extensions = vec![
+            "8BITMIME".to_owned()
+        ];
+
+        if self.session.config.tls.is_some() && !self.session.is_tls() {
+            extensions.push("STARTTLS".to_owned());
+        }
+
+        extensions.push(format!("SIZE {}", self.session.config.max_message_size));
+        extensions.push("SMTPUTF8".to_owned());
+        extensions.push("PIPELINING".to_owned());
+        extensions.push("CHUNKING".to_owned());
+        extensions.push("BINARYMIME".to_owned());
+        extensions.push("ENHANCEDSTATUSCODES".to_owned());
+        extensions.push("DSN".to_owned());
+
+        if self.session.auth_available() {
+            extensions.push("AUTH PLAIN LOGIN".to_owned());
+        }
+
+        extensions
+    }
+
+    /// Describe a command, or list supported commands and extensions without topic
+    fn help(&self, topic: Option<String>) -> ServerCommand {
+        let config = &self.session.config;
+        let available = HELP_TOPICS.iter()
+            .filter(|(command, _)| match *command {
+                "STARTTLS" => config.tls.is_some(),
+                "AUTH" => config.auth.is_some(),
+                _ => true
+            });
+
+        match topic {
+            Some(topic) => match available.clone().find(|(command, _)| command.eq_ignore_ascii_case(topic.trim())) {
+                Some((_, usage)) => ServerCommand::HelpOk(vec![usage.to_string()]),
+                None => ServerCommand::HelpTopicUnknown(topic)
+            },
+            None => ServerCommand::HelpOk(vec![
+                format!("{} SMTP to DTN gateway", config.domain),
+                format!("Commands: {}", available.map(|(command, _)| *command).collect::<Vec<_>>().join(" ")),
+                format!("Extensions: {}", self.extensions().join(", ")),
+                "Use HELP <command> for command usage".to_owned()
+            ])
+        }
+    }
+
+    pub fn new(smtp_session: Session) -> Result<Self, io::Error> {
         let command_iter = match smtp_session.recv_commands() {
             Ok(iter) => iter,
             Err(e) => return Err(e)
         };
 
-        Ok(Self { session: smtp_session, commands: command_iter })
+        Ok(Self {
+            session: smtp_session,
+            commands: command_iter,
+            pending_mail: false,
+            user: None,
+            state: SessionState::Connected,
+            client_domain: None,
+            extended: false
+        })
+    }
+
+    /// Confirm delivery of the last received mail to the client
+    pub fn accept(&mut self) -> Result<(), io::Error> {
+        self.pending_mail = false;
+        self.session.send_command(ServerCommand::MailOk)
+    }
+
+    /// Report a transient failure for the last received mail so that the client retries later
+    pub fn defer(&mut self, reason: String) -> Result<(), io::Error> {
+        self.pending_mail = false;
+        self.session.send_command(ServerCommand::LocalError(reason))
+    }
+
+    /// Report that the last received mail could not be stored so that the client retries later
+    pub fn defer_storage(&mut self) -> Result<(), io::Error> {
+        self.pending_mail = false;
+        self.session.send_command(ServerCommand::InsufficientStorage)
+    }
+
+    /// Reply to send instead of processing the command if it is not allowed in the current state
+    fn check_sequence(&self, command: &ClientCommand) -> Option<ServerCommand> {
+        let reason = match (command, self.state) {
+            (ClientCommand::Hello(_) | ClientCommand::ExtendedHello(_), state) if state != SessionState::Connected =>
+                "Already greeted",
+            (ClientCommand::Mail(..) | ClientCommand::Recipient(..) | ClientCommand::Data | ClientCommand::Bdat { .. } |
+                ClientCommand::StartTls | ClientCommand::Auth(..), SessionState::Connected) =>
+                "Send HELO or EHLO first",
+            (ClientCommand::Mail(..), SessionState::Mail | SessionState::Recipient) =>
+                "Mail transaction already started",
+            (ClientCommand::Recipient(..) | ClientCommand::Data | ClientCommand::Bdat { .. }, SessionState::Greeted) =>
+                "No mail transaction. Begin with a MAIL command",
+            (ClientCommand::Data | ClientCommand::Bdat { .. }, SessionState::Mail) =>
+                "No valid recipients",
+            (ClientCommand::StartTls | ClientCommand::Auth(..), SessionState::Mail | SessionState::Recipient) =>
+                "Mail transaction in progress",
+            (ClientCommand::Mail(..) | ClientCommand::Recipient(..) | ClientCommand::Data |
+                ClientCommand::StartTls | ClientCommand::Auth(..), SessionState::Chunking) =>
+                "BDAT transfer in progress",
+            _ => return None
+        };
+
+        Some(ServerCommand::BadSequenceOfCommand(reason.to_owned()))
+    }
+
+    /// Send a SASL challenge and read the decoded client response
+    ///
+    /// Returns the reply to send instead if the client aborted or sent an invalid response.
+    fn auth_challenge(&mut self, challenge: &str) -> Result<Result<String, ServerCommand>, io::Error> {
+        self.session.send_command(ServerCommand::AuthContinue(challenge.to_owned()))?;
+
+        match self.commands.read_line(MAX_AUTH_LINE_LENGTH) {
+            Some(Ok(line)) => Ok(decode_auth_response(line.strip_suffix(b"\r\n").unwrap_or(&line))),
+            Some(Err(SmtpError::Io(e))) => Err(e),
+            Some(Err(SmtpError::Timeout)) => Err(io::ErrorKind::TimedOut.into()),
+            Some(Err(_)) => Ok(Err(ServerCommand::LineTooLong)),
+            None => Err(io::ErrorKind::UnexpectedEof.into())
+        }
+    }
+
+    /// Read the username and password of a SASL exchange
+    fn auth_credentials(&mut self, mechanism: AuthMechanism, initial_response: Option<String>) -> Result<Result<(String, String), ServerCommand>, io::Error> {
+        let initial_response = match initial_response.map(|it| decode_auth_response(it.as_bytes())) {
+            Some(Ok(response)) => Some(response),
+            Some(Err(reply)) => return Ok(Err(reply)),
+            None => None
+        };
+
+        match mechanism {
+            AuthMechanism::Plain => {
+                let response = match initial_response {
+                    Some(response) => response,
+                    None => match self.auth_challenge("")? {
+                        Ok(response) => response,
+                        Err(reply) => return Ok(Err(reply))
+                    }
+                };
+
+                // authorization-id NUL authentication-id NUL password
+                let mut fields = response.splitn(3, '\0');
+                let (Some(authorization), Some(username), Some(password)) = (fields.next(), fields.next(), fields.next()) else {
+                    return Ok(Err(ServerCommand::SyntaxError("Invalid PLAIN response".to_owned())));
+                };
+
+                if !authorization.is_empty() && authorization != username {
+                    return Ok(Err(ServerCommand::AuthInvalid));
+                }
+
+                Ok(Ok((username.to_owned(), password.to_owned())))
+            },
+            AuthMechanism::Login => {
+                let username = match initial_response {
+                    Some(username) => username,
+                    None => match self.auth_challenge("Username:")? {
+                        Ok(username) => username,
+                        Err(reply) => return Ok(Err(reply))
+                    }
+                };
+
+                match self.auth_challenge("Password:")? {
+                    Ok(password) => Ok(Ok((username, password))),
+                    Err(reply) => Ok(Err(reply))
+                }
+            },
+        }
     }
 }
 
@@ -422,66 +1431,248 @@ impl Iterator for MailReceiver {
     fn next(&mut self) -> Option<Self::Item> {
         let mut current_mail: Option<Mail> = None;
 
-        for command in &mut self.commands {
+        if self.pending_mail {
+            warn!("Previous mail was never acknowledged");
+            if let Err(e) = self.defer("mail was not processed".to_owned()) {
+                return Some(Err(e));
+            }
+        }
+
+        loop {
+            if let Err(e) = self.commands.source.set_read_timeout(self.command_timeout()) {
+                return Some(Err(e));
+            }
+
+            let Some(command) = self.commands.next() else {
+                break;
+            };
+
             match command {
                 Ok(command) => {
+                    if let Some(reply) = self.check_sequence(&command) {
+                        if let ClientCommand::Bdat { size, .. } = command {
+                            // Chunk data follows the command even if it is refused
+                            match self.commands.read_chunk(size, false) {
+                                Err(e) if is_timeout(&e) => return self.close_on_timeout(),
+                                Err(e) => return Some(Err(e)),
+                                Ok(_) => {}
+                            }
+                        }
+
+                        if let Err(e) = self.session.send_command(reply) {
+                            return Some(Err(e))
+                        }
+                        continue;
+                    }
+
                     match command {
 
-                        ClientCommand::Hello(domain) => if let Err(e) = self.session.send_command(ServerCommand::HelloOk { 
-                                domain,
-                                greet: Some("delayed greetings !".to_owned()),
-                                extensions: vec![
-                                    "8BITMIME".to_owned()
-                                ]
-                            }) {
-                                return Some(Err(e))
+                        ClientCommand::Hello(domain) => {
+                            self.state = SessionState::Greeted;
+                            self.client_domain = Some(domain);
+                            self.extended = false;
+
+                            if let Err(e) = self.session.send_command(ServerCommand::HelloOk { 
+                                    domain: self.session.config.domain.clone(),
+                                    greet: Some("delayed greetings !".to_owned()),
+                                    extensions: Vec::new()
+                                }) {
+                                    return Some(Err(e))
+                            }
                         },
 
-                        ClientCommand::Mail(from_address) => {
-                            match &mut current_mail {
-                                Some(_) => {
-                                    if let Err(e) = self.session.send_command(ServerCommand::BadSequenceOfCommand("Mail sequence already started".to_owned())) {
+                        ClientCommand::ExtendedHello(domain) => {
+                            self.state = SessionState::Greeted;
+                            self.client_domain = Some(domain);
+                            self.extended = true;
+
+                            if let Err(e) = self.session.send_command(ServerCommand::HelloOk { 
+                                    domain: self.session.config.domain.clone(),
+                                    greet: Some("delayed greetings !".to_owned()),
+                                    extensions: self.extensions()
+                                }) {
+                                    return Some(Err(e))
+                            }
+                        },
+
+                        ClientCommand::StartTls => {
+                            let Some(tls_config) = self.session.config.tls.clone() else {
+                                if let Err(e) = self.session.send_command(ServerCommand::CommandNotImplemented) {
+                                    return Some(Err(e))
+                                }
+                                continue;
+                            };
+
+                            if self.session.is_tls() {
+                                if let Err(e) = self.session.send_command(ServerCommand::BadSequenceOfCommand("TLS already active".to_owned())) {
+                                    return Some(Err(e))
+                                }
+                                continue;
+                            }
+
+                            let connection = match ServerConnection::new(tls_config) {
+                                Ok(connection) => connection,
+                                Err(e) => {
+                                    error!("Failed to start TLS : {e}");
+                                    if let Err(e) = self.session.send_command(ServerCommand::TlsNotAvailable) {
                                         return Some(Err(e))
                                     }
-                                },
-                                None => {
-                                    current_mail = Some(Mail::new(from_address));
-                                    if let Err(e) = self.session.send_command(ServerCommand::SenderOk) {
-                                        return Some(Err(e));
-                                    }
+                                    continue;
                                 }
+                            };
+
+                            if let Err(e) = self.session.send_command(ServerCommand::ReadyToStartTls) {
+                                return Some(Err(e))
+                            }
+
+                            // Anything sent before the handshake must not be trusted
+                            self.commands.buffer.clear();
+                            self.state = SessionState::Connected;
+                            self.client_domain = None;
+                            self.user = None;
+
+                            if let Err(e) = self.session.source.start_tls(connection) {
+                                return Some(Err(e))
                             }
                         },
 
-                        ClientCommand::Recipient(recipient_address) => {
-                            match &mut current_mail {
-                                Some(m) => {
-                                    m.receipients.push(recipient_address);
-                                    if let Err(e) = self.session.send_command(ServerCommand::RecipientOk) {
-                                        return Some(Err(e))
-                                    }
-                                },
-                                None => {
-                                    if let Err(e) = self.session.send_command(ServerCommand::BadSequenceOfCommand("No mail sequence. Begin with a MAIL command".to_owned())) {
-                                        return Some(Err(e))
+                        ClientCommand::Auth(mechanism, initial_response) => {
+                            let Some(backend) = self.session.config.auth.clone() else {
+                                if let Err(e) = self.session.send_command(ServerCommand::CommandNotImplemented) {
+                                    return Some(Err(e))
+                                }
+                                continue;
+                            };
+
+                            let reply = if !self.session.auth_available() {
+                                ServerCommand::EncryptionRequired
+                            } else if self.user.is_some() {
+                                ServerCommand::BadSequenceOfCommand("Already authenticated".to_owned())
+                            } else {
+                                match self.auth_credentials(mechanism, initial_response) {
+                                    Err(e) if is_timeout(&e) => return self.close_on_timeout(),
+                                    Err(e) => return Some(Err(e)),
+                                    Ok(Err(reply)) => reply,
+                                    Ok(Ok((username, password))) => match backend.authenticate(&username, &password) {
+                                        Some(user) => {
+                                            info!("User {} authenticated", user.name);
+                                            self.user = Some(user);
+                                            ServerCommand::AuthOk
+                                        },
+                                        None => {
+                                            warn!("Authentication failed for user {username}");
+                                            ServerCommand::AuthInvalid
+                                        }
                                     }
                                 }
+                            };
+
+                            if let Err(e) = self.session.send_command(reply) {
+                                return Some(Err(e))
+                            }
+                        },
+
+                        ClientCommand::Mail(_, _) if self.session.config.require_tls && !self.session.is_tls() => {
+                            if let Err(e) = self.session.send_command(ServerCommand::TlsRequired) {
+                                return Some(Err(e))
+                            }
+                        },
+
+                        ClientCommand::Mail(_, _) if self.session.config.require_auth && self.user.is_none() => {
+                            if let Err(e) = self.session.send_command(ServerCommand::AuthRequired) {
+                                return Some(Err(e))
+                            }
+                        },
+
+                        ClientCommand::Mail(from_address, _) if self.session.config.sender_policy == SenderPolicy::OwnAddresses
+                            && self.user.is_none()
+                            && self.session.config.auth.as_ref().is_some_and(|backend| backend.is_user_address(&from_address)) => {
+                            if let Err(e) = self.session.send_command(ServerCommand::AuthRequired) {
+                                return Some(Err(e))
+                            }
+                        },
+
+                        ClientCommand::Mail(from_address, _) if self.session.config.sender_policy == SenderPolicy::OwnAddresses
+                            && self.user.as_ref().is_some_and(|user| !user.owns(&from_address)) => {
+                            if let Err(e) = self.session.send_command(ServerCommand::SenderNotAllowed) {
+                                return Some(Err(e))
+                            }
+                        },
+
+                        ClientCommand::Mail(_, MailParameters { size: Some(size), .. }) if size > self.session.config.max_message_size => {
+                            if let Err(e) = self.session.send_command(ServerCommand::MessageTooLarge) {
+                                return Some(Err(e))
+                            }
+                        },
+
+                        ClientCommand::Mail(from_address, parameters) if !parameters.smtputf8 && !from_address.is_ascii() => {
+                            if let Err(e) = self.session.send_command(ServerCommand::Utf8AddressNotAllowed) {
+                                return Some(Err(e))
+                            }
+                        },
+
+                        // DSN is only advertised in reply to EHLO (RFC 3461 section 4)
+                        ClientCommand::Mail(_, parameters) if !self.extended
+                            && (parameters.ret.is_some() || parameters.envid.is_some()) => {
+                            if let Err(e) = self.session.send_command(ServerCommand::ParameterNotRecognized("DSN extension not advertised".to_owned())) {
+                                return Some(Err(e))
+                            }
+                        },
+
+                        ClientCommand::Mail(from_address, parameters) => {
+                            current_mail = Some(Mail::new(from_address, parameters));
+                            self.state = SessionState::Mail;
+                            if let Err(e) = self.session.send_command(ServerCommand::SenderOk) {
+                                return Some(Err(e));
+                            }
+                        },
+
+                        ClientCommand::Recipient(recipient_address, _) if !recipient_address.is_ascii()
+                            && current_mail.as_ref().is_some_and(|m| !m.parameters.smtputf8) => {
+                            if let Err(e) = self.session.send_command(ServerCommand::Utf8AddressNotAllowed) {
+                                return Some(Err(e))
+                            }
+                        },
+
+                        ClientCommand::Recipient(_, parameters) if !self.extended
+                            && (parameters.notify.is_some() || parameters.orcpt.is_some()) => {
+                            if let Err(e) = self.session.send_command(ServerCommand::ParameterNotRecognized("DSN extension not advertised".to_owned())) {
+                                return Some(Err(e))
+                            }
+                        },
+
+                        ClientCommand::Recipient(recipient_address, parameters) => {
+                            if let Some(m) = &mut current_mail {
+                                m.receipients.push(Recipient { address: recipient_address, parameters });
+                            }
+                            self.state = SessionState::Recipient;
+                            if let Err(e) = self.session.send_command(ServerCommand::RecipientOk) {
+                                return Some(Err(e))
+                            }
+                        },
+
+                        ClientCommand::Data if current_mail.as_ref()
+                            .is_some_and(|m| m.parameters.body == Some(BodyType::BinaryMime)) => {
+                            if let Err(e) = self.session.send_command(ServerCommand::BadSequenceOfCommand("BINARYMIME content must be sent with BDAT".to_owned())) {
+                                return Some(Err(e))
                             }
                         },
 
                         ClientCommand::Data => {
+                            self.state = SessionState::Data;
+                            self.commands.data = true;
                             if let Err(e) = self.session.send_command(ServerCommand::StartMailInput) {
                                 return Some(Err(e))
                             }
                         },
 
                         ClientCommand::MailInput(content) => {
+                            self.state = SessionState::Greeted;
                             match current_mail.take() {
                                 Some(mut m) => {
                                     m.content = content;
-                                    if let Err(e) = self.session.send_command(ServerCommand::MailOk) {
-                                        return Some(Err(e));
-                                    }
+                                    self.trace(&mut m);
+                                    self.pending_mail = true;
                                     return Some(Ok(m));
                                 },
                                 None => {
@@ -492,6 +1683,44 @@ impl Iterator for MailReceiver {
                             }
                         },
 
+                        ClientCommand::Bdat { size, last } => {
+                            let too_large = current_mail.as_ref()
+                                .is_some_and(|m| m.content.len().saturating_add(size) > self.session.config.max_message_size);
+
+                            let mut chunk = match self.commands.read_chunk(size, !too_large) {
+                                Ok(chunk) => chunk,
+                                Err(e) if is_timeout(&e) => return self.close_on_timeout(),
+                                Err(e) => return Some(Err(e))
+                            };
+
+                            if too_large {
+                                current_mail = None;
+                                self.state = SessionState::Greeted;
+                                if let Err(e) = self.session.send_command(ServerCommand::MessageTooLarge) {
+                                    return Some(Err(e))
+                                }
+                                continue;
+                            }
+
+                            if let Some(m) = &mut current_mail {
+                                m.content.append(&mut chunk);
+                            }
+
+                            if last {
+                                self.state = SessionState::Greeted;
+                                if let Some(mut m) = current_mail.take() {
+                                    self.trace(&mut m);
+                                    self.pending_mail = true;
+                                    return Some(Ok(m));
+                                }
+                            } else {
+                                self.state = SessionState::Chunking;
+                                if let Err(e) = self.session.send_command(ServerCommand::ChunkOk(size)) {
+                                    return Some(Err(e))
+                                }
+                            }
+                        },
+
                         ClientCommand::Quit => {
                             if let Err(e) = self.session.send_command(ServerCommand::ClosingConnection) {
                                 return Some(Err(e))
@@ -499,14 +1728,33 @@ impl Iterator for MailReceiver {
                             break;
                         },
 
-                        ClientCommand::Expand(_) => {
-                            if let Err(e) = self.session.send_command(ServerCommand::CommandNotImplemented) {
+                        ClientCommand::Expand(list) => {
+                            let reply = match &self.session.config.directory {
+                                _ if self.session.config.verify_privacy => ServerCommand::CannotVerify,
+                                None => ServerCommand::CommandNotImplemented,
+                                Some(directory) => match directory.expand(&list) {
+                                    Some(members) => ServerCommand::ListExpanded(members.iter().map(ToString::to_string).collect()),
+                                    None => ServerCommand::ListUnknown(list)
+                                }
+                            };
+
+                            if let Err(e) = self.session.send_command(reply) {
                                 return Some(Err(e))
                             }
                         },
 
-                        ClientCommand::Verify(_) => {
-                            if let Err(e) = self.session.send_command(ServerCommand::CommandNotImplemented) {
+                        ClientCommand::Verify(query) => {
+                            let reply = match &self.session.config.directory {
+                                _ if self.session.config.verify_privacy => ServerCommand::CannotVerify,
+                                None => ServerCommand::CommandNotImplemented,
+                                Some(directory) => match &directory.verify(&query)[..] {
+                                    [] => ServerCommand::UserUnknown(query),
+                                    [user] => ServerCommand::UserVerified(user.to_string()),
+                                    users => ServerCommand::UserAmbiguous(users.iter().map(ToString::to_string).collect())
+                                }
+                            };
+
+                            if let Err(e) = self.session.send_command(reply) {
                                 return Some(Err(e))
                             }
                         },
@@ -519,40 +1767,69 @@ impl Iterator for MailReceiver {
 
                         ClientCommand::Reset => {
                             current_mail = None;
+                            if self.state != SessionState::Connected {
+                                self.state = SessionState::Greeted;
+                            }
                             if let Err(e) = self.session.send_command(ServerCommand::ResetOk) {
                                 return Some(Err(e))
                             }
                         },
 
-                        ClientCommand::Help(_) => {
-                            if let Err(e) = self.session.send_command(ServerCommand::CommandNotImplemented) {
+                        ClientCommand::Help(topic) => {
+                            if let Err(e) = self.session.send_command(self.help(topic)) {
                                 return Some(Err(e))
                             }
                         }
                     }
                 },
                 Err(SmtpError::Command(e)) => {
-                    match e {
+                    let reason = e.to_string();
+                    let reply = match e {
                         ClientCommandParseError::BadEol |
                         ClientCommandParseError::InvalidCharacter(_) |
                         ClientCommandParseError::InvalidCommandCharacter |
-                        ClientCommandParseError::SyntaxInvalid |
+                        ClientCommandParseError::SyntaxInvalid =>
+                            ServerCommand::SyntaxError(reason),
                         ClientCommandParseError::MissingDomain |
-                        ClientCommandParseError::InvalidRecipient(_) |
-                        ClientCommandParseError::InvalidFrom(_) |
-                        ClientCommandParseError::MissingParameter => {
-                            if let Err(e) = self.session.send_command(ServerCommand::SyntaxError) {
-                                return Some(Err(e))
-                            }
-                        },
+                        ClientCommandParseError::InvalidParameter(_) |
+                        ClientCommandParseError::MissingParameter =>
+                            ServerCommand::InvalidArguments(reason),
+                        ClientCommandParseError::InvalidFrom(e) =>
+                            ServerCommand::BadSenderAddress(e.to_string()),
+                        ClientCommandParseError::InvalidRecipient(e) =>
+                            ServerCommand::BadRecipientAddress(e.to_string()),
+                        ClientCommandParseError::UnknownParameter(_) =>
+                            ServerCommand::ParameterNotRecognized(reason),
+                        ClientCommandParseError::UnknownAuthMechanism(mechanism) =>
+                            ServerCommand::AuthMechanismUnknown(mechanism),
                         ClientCommandParseError::MissingCommand |
-                        ClientCommandParseError::InvalidCommand(_) => {
-                            if let Err(e) = self.session.send_command(ServerCommand::CommandUnrecognized) {
-                                return Some(Err(e))
-                            }
-                        }
+                        ClientCommandParseError::InvalidCommand(_) =>
+                            ServerCommand::CommandUnrecognized(reason)
+                    };
+
+                    if let Err(e) = self.session.send_command(reply) {
+                        return Some(Err(e))
                     }
                 }
+                Err(SmtpError::LineTooLong) => {
+                    if let Err(e) = self.session.send_command(ServerCommand::LineTooLong) {
+                        return Some(Err(e))
+                    }
+                },
+                Err(SmtpError::MessageTooLarge) => {
+                    current_mail = None;
+                    self.state = SessionState::Greeted;
+                    if let Err(e) = self.session.send_command(ServerCommand::MessageTooLarge) {
+                        return Some(Err(e))
+                    }
+                },
+                Err(SmtpError::ChunkTooLarge) => {
+                    if let Err(e) = self.session.send_command(ServerCommand::MessageTooLarge) {
+                        return Some(Err(e))
+                    }
+                    break;
+                },
+                Err(SmtpError::Timeout) => return self.close_on_timeout(),
                 Err(SmtpError::Io(e)) => error!("Failed to read commands : {e}")
             }
             
@@ -564,55 +1841,259 @@ impl Iterator for MailReceiver {
     }
 }
 
-#[derive(Debug)]
-pub struct EmailAddress(String);
-
-impl Deref for EmailAddress {
-    type Target = str;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmailAddress {
+    /// Null reverse-path `<>` used as sender of bounces and notifications
+    Null,
+    /// Unqualified `<postmaster>` recipient, addressing the local node
+    Postmaster,
+    Mailbox(String)
+}
 
-    fn deref(&self) -> &Self::Target {
-        self.0.as_str()
+impl Display for EmailAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<{}>", self.address())
     }
 }
 
 #[derive(Debug, Error)]
 pub enum BadAddressError {
-    #[error("Recipient address must start with < and finish with >")]
+    #[error("Address must start with < and finish with >")]
     BadFrame,
     #[error("Missing @ in email address")]
     AtMissing,
+    #[error("Null address is not a valid recipient")]
+    NullRecipient,
+    #[error("Postmaster is not a valid sender")]
+    PostmasterSender,
+    #[error("Invalid local part in email address")]
+    InvalidLocalPart,
+    #[error("Invalid domain in email address")]
+    InvalidDomain,
     #[error("Invalid UTF-8 string")]
     InvalidUtf8String(#[from] FromUtf8Error)
 }
 
+/// Whether a header field is present in a mail header section
+fn has_header(headers: &[u8], name: &str) -> bool {
+    headers.split(|it| *it == b'\n')
+        .any(|line| line.len() > name.len() && line[name.len()] == b':'
+            && line[..name.len()].eq_ignore_ascii_case(name.as_bytes()))
+}
+
+/// Decode a base64 SASL response, `*` meaning the client aborted the exchange
+fn decode_auth_response(response: &[u8]) -> Result<String, ServerCommand> {
+    match response {
+        b"*" => Err(ServerCommand::AuthAborted),
+        b"=" => Ok(String::new()),
+        response => BASE64_STANDARD.decode(response).ok()
+            .and_then(|it| String::from_utf8(it).ok())
+            .ok_or(ServerCommand::SyntaxError("Invalid base64 response".to_owned()))
+    }
+}
+
 impl EmailAddress {
     pub fn from_bytes(source: Vec<u8>) -> Result<Self, BadAddressError> {
-        if ! ( source.starts_with(b"<") && source.ends_with(b">") ) {
+        if ! ( source.len() >= 2 && source.starts_with(b"<") && source.ends_with(b">") ) {
             return Err(BadAddressError::BadFrame)
         }
 
-        if ! ( source.contains(&b'@') ) {
+        let address = &source[1..source.len()-1];
+
+        if address.is_empty() {
+            return Ok(Self::Null)
+        }
+
+        if address.eq_ignore_ascii_case(b"postmaster") {
+            return Ok(Self::Postmaster)
+        }
+
+        let address = String::from_utf8(address.to_vec())?;
+
+        let Some((local_part, domain)) = address.rsplit_once('@') else {
             return Err(BadAddressError::AtMissing)
+        };
+
+        if !is_valid_local_part(local_part) {
+            return Err(BadAddressError::InvalidLocalPart)
+        }
+
+        let is_address_literal = domain.starts_with('[') && domain.ends_with(']');
+        if !is_address_literal && node_name(domain).is_none() {
+            return Err(BadAddressError::InvalidDomain)
         }
 
-        return Ok(Self(String::from_utf8(source)?))
+        Ok(Self::Mailbox(address))
     }
 
-    pub fn domain(&self) -> &str {
-        let result = &self.0.split_once("@").unwrap().1;
-        &result[..result.len()-1]
+    /// Whether the address can be used without the SMTPUTF8 extension
+    pub fn is_ascii(&self) -> bool {
+        self.address().is_ascii()
+    }
+
+    /// Address without its enclosing angle brackets
+    pub fn address(&self) -> &str {
+        match self {
+            EmailAddress::Null => "",
+            EmailAddress::Postmaster => "postmaster",
+            EmailAddress::Mailbox(address) => address,
+        }
+    }
+
+    /// Domain of the address in its ASCII form, `None` for null and unqualified postmaster addresses
+    pub fn domain(&self) -> Option<String> {
+        match self {
+            EmailAddress::Mailbox(address) => address.rsplit_once('@')
+                .map(|(_, domain)| node_name(domain).unwrap_or_else(|| domain.to_ascii_lowercase())),
+            _ => None
+        }
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct Recipient {
+    pub address: EmailAddress,
+    pub parameters: RcptParameters
+}
+
 #[derive(Debug)]
 pub struct Mail {
     pub from: EmailAddress,
-    pub receipients: Vec<EmailAddress>,
+    pub parameters: MailParameters,
+    pub receipients: Vec<Recipient>,
     pub content: Vec<u8>
 }
 
 impl Mail {
-    pub fn new(from_address: EmailAddress) -> Self {
-        Self { from: from_address, receipients: Vec::new(), content: Vec::new() }
+    pub fn new(from_address: EmailAddress, parameters: MailParameters) -> Self {
+        Self { from: from_address, parameters, receipients: Vec::new(), content: Vec::new() }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<ClientCommand, ClientCommandParseError> {
+        ClientCommand::from_bytes(format!("{line}\r\n").as_bytes())
+    }
+
+    fn mail(line: &str) -> (EmailAddress, MailParameters) {
+        match parse(line) {
+            Ok(ClientCommand::Mail(from, parameters)) => (from, parameters),
+            other => panic!("{line} parsed as {other:?}")
+        }
+    }
+
+    fn recipient(line: &str) -> (EmailAddress, RcptParameters) {
+        match parse(line) {
+            Ok(ClientCommand::Recipient(to, parameters)) => (to, parameters),
+            other => panic!("{line} parsed as {other:?}")
+        }
+    }
+
+    #[test]
+    fn path_is_split_from_parameters() {
+        let (path, parameters) = split_path_parameters(b"from:<alice@node1>  size=12 SMTPUTF8", b"FROM:").unwrap();
+        assert_eq!(path, b"<alice@node1>");
+        assert_eq!(parameters, [("SIZE".to_owned(), Some("12")), ("SMTPUTF8".to_owned(), None)]);
+
+        assert!(matches!(split_path_parameters(b"TO:alice@node1", b"TO:"), Err(ClientCommandParseError::SyntaxInvalid)));
+        assert!(matches!(split_path_parameters(b"TO:<alice@node1", b"TO:"), Err(ClientCommandParseError::SyntaxInvalid)));
+        assert!(matches!(split_path_parameters(b"FROM:<alice@node1>", b"TO:"), Err(ClientCommandParseError::SyntaxInvalid)));
+    }
+
+    #[test]
+    fn space_after_colon_is_accepted() {
+        let (from, _) = mail("MAIL FROM: <alice@node1>");
+        assert_eq!(from, EmailAddress::Mailbox("alice@node1".to_owned()));
+
+        let (to, _) = recipient("RCPT TO: <bob@node2>");
+        assert_eq!(to, EmailAddress::Mailbox("bob@node2".to_owned()));
+    }
+
+    #[test]
+    fn xtext_is_decoded() {
+        assert_eq!(decode_xtext("QQ314159").as_deref(), Some("QQ314159"));
+        assert_eq!(decode_xtext("a+2Bb+3Dc").as_deref(), Some("a+b=c"));
+        assert_eq!(decode_xtext("a+2"), None);
+        assert_eq!(decode_xtext("a+ZZ"), None);
+    }
+
+    #[test]
+    fn mail_parameters_are_parsed() {
+        let (from, parameters) = mail("MAIL FROM:<alice@node1> body=8bitmime SIZE=1024 SMTPUTF8 RET=HDRS ENVID=QQ+2B1 AUTH=alice+40node1");
+        assert_eq!(from, EmailAddress::Mailbox("alice@node1".to_owned()));
+        assert_eq!(parameters.body, Some(BodyType::EightBitMime));
+        assert_eq!(parameters.size, Some(1024));
+        assert!(parameters.smtputf8);
+        assert_eq!(parameters.ret, Some(DsnReturn::Headers));
+        assert_eq!(parameters.envid.as_deref(), Some("QQ+1"));
+        assert_eq!(parameters.auth.as_deref(), Some("alice@node1"));
+
+        let (from, _) = mail("MAIL FROM:<>");
+        assert_eq!(from, EmailAddress::Null);
+    }
+
+    #[test]
+    fn invalid_mail_parameters_are_refused() {
+        assert!(matches!(parse("MAIL FROM:<alice@node1> BODY=9BIT"), Err(ClientCommandParseError::InvalidParameter(_))));
+        assert!(matches!(parse("MAIL FROM:<alice@node1> SIZE=big"), Err(ClientCommandParseError::InvalidParameter(_))));
+        assert!(matches!(parse("MAIL FROM:<alice@node1> SMTPUTF8=yes"), Err(ClientCommandParseError::InvalidParameter(_))));
+        assert!(matches!(parse("MAIL FROM:<alice@node1> RET"), Err(ClientCommandParseError::InvalidParameter(_))));
+        assert!(matches!(parse("MAIL FROM:<alice@node1> ENVID=a+Z"), Err(ClientCommandParseError::InvalidParameter(_))));
+        assert!(matches!(parse("MAIL FROM:<postmaster>"), Err(ClientCommandParseError::InvalidFrom(BadAddressError::PostmasterSender))));
+    }
+
+    #[test]
+    fn duplicate_parameters_are_refused() {
+        assert!(matches!(parse("MAIL FROM:<alice@node1> SIZE=1 size=2"), Err(ClientCommandParseError::InvalidParameter(_))));
+        assert!(matches!(parse("MAIL FROM:<alice@node1> RET=FULL RET=HDRS"), Err(ClientCommandParseError::InvalidParameter(_))));
+        assert!(matches!(parse("RCPT TO:<bob@node2> NOTIFY=NEVER NOTIFY=SUCCESS"), Err(ClientCommandParseError::InvalidParameter(_))));
+        assert!(matches!(parse("RCPT TO:<bob@node2> ORCPT=rfc822;a@b ORCPT=rfc822;c@d"), Err(ClientCommandParseError::InvalidParameter(_))));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn unknown_parameters_are_not_recognized() {
+        assert!(matches!(parse("MAIL FROM:<alice@node1> MT-PRIORITY=3"), Err(ClientCommandParseError::UnknownParameter(keyword)) if keyword == "MT-PRIORITY"));
+        assert!(matches!(parse("RCPT TO:<bob@node2> SIZE=12"), Err(ClientCommandParseError::UnknownParameter(keyword)) if keyword == "SIZE"));
+    }
+
+    #[test]
+    fn recipient_parameters_are_parsed() {
+        let (to, parameters) = recipient("RCPT TO:<bob@node2> NOTIFY=success,Delay ORCPT=rfc822;bob+2Bdtn@example.org");
+        assert_eq!(to, EmailAddress::Mailbox("bob@node2".to_owned()));
+        assert_eq!(parameters.notify, Some(DsnNotify { success: true, failure: false, delay: true }));
+        assert_eq!(parameters.orcpt, Some(OriginalRecipient {
+            address_type: "rfc822".to_owned(),
+            address: "bob+dtn@example.org".to_owned()
+        }));
+
+        let (_, parameters) = recipient("RCPT TO:<bob@node2> NOTIFY=NEVER");
+        assert_eq!(parameters.notify, Some(DsnNotify::default()));
+
+        let (to, parameters) = recipient("RCPT TO:<Postmaster>");
+        assert_eq!(to, EmailAddress::Postmaster);
+        assert_eq!(parameters.notify, None);
+    }
+
+    #[test]
+    fn invalid_recipient_parameters_are_refused() {
+        assert!(matches!(parse("RCPT TO:<bob@node2> NOTIFY=NEVER,SUCCESS"), Err(ClientCommandParseError::InvalidParameter(_))));
+        assert!(matches!(parse("RCPT TO:<bob@node2> NOTIFY=SOMETIMES"), Err(ClientCommandParseError::InvalidParameter(_))));
+        assert!(matches!(parse("RCPT TO:<bob@node2> ORCPT=bob@example.org"), Err(ClientCommandParseError::InvalidParameter(_))));
+        assert!(matches!(parse("RCPT TO:<>"), Err(ClientCommandParseError::InvalidRecipient(BadAddressError::NullRecipient))));
+    }
+
+    #[test]
+    fn bdat_arguments_are_parsed() {
+        assert!(matches!(parse("BDAT 5"), Ok(ClientCommand::Bdat { size: 5, last: false })));
+        assert!(matches!(parse("bdat 0  last"), Ok(ClientCommand::Bdat { size: 0, last: true })));
+        assert!(matches!(parse("BDAT 99999999999999999999999 LAST"), Ok(ClientCommand::Bdat { size: usize::MAX, last: true })));
+
+        assert!(matches!(parse("BDAT"), Err(ClientCommandParseError::MissingParameter)));
+        assert!(matches!(parse("BDAT -1"), Err(ClientCommandParseError::SyntaxInvalid)));
+        assert!(matches!(parse("BDAT +5"), Err(ClientCommandParseError::SyntaxInvalid)));
+        assert!(matches!(parse("BDAT 5 MORE"), Err(ClientCommandParseError::SyntaxInvalid)));
+        assert!(matches!(parse("BDAT 5 LAST LAST"), Err(ClientCommandParseError::SyntaxInvalid)));
+    }
+}
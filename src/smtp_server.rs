@@ -1,14 +1,69 @@
-use std::{net::TcpListener, sync::mpsc::Sender};
+use std::{net::{TcpListener, TcpStream}, path::PathBuf, sync::{atomic::{AtomicUsize, Ordering}, mpsc::Sender, Arc}, thread};
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use rustls::{pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer}, ServerConfig};
+use thiserror::Error;
 
-use crate::{mail_sender::SenderMsg, smtp::Session};
+use crate::{auth::{AuthBackend, FileAuthBackend, SenderPolicy}, directory::{Directory, FileDirectory}, mail_sender::{submit_mail, SenderMsg, SubmissionError}, smtp::{Session, SessionConfig, SmtpTimeouts}, spool::Spool};
+
+pub const DEFAULT_MAX_SESSIONS: usize = 16;
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
 
 pub struct SmtpConfig {
-    pub bind: String
+    pub bind: String,
+    pub node_eid: String,
+    pub max_sessions: usize,
+    pub max_message_size: usize,
+    pub tls: Option<TlsConfig>,
+    pub require_tls: bool,
+    pub auth: Option<AuthConfig>,
+    pub directory: Option<PathBuf>,
+    pub verify_privacy: bool,
+    pub timeouts: SmtpTimeouts
+}
+
+pub struct AuthConfig {
+    pub credentials: PathBuf,
+    pub require_auth: bool,
+    pub sender_policy: SenderPolicy
+}
+
+pub struct TlsConfig {
+    pub certificate: PathBuf,
+    pub private_key: PathBuf
+}
+
+#[derive(Debug, Error)]
+pub enum TlsConfigError {
+    #[error("Failed to read PEM file : {0}")]
+    Pem(#[from] rustls::pki_types::pem::Error),
+    #[error("Invalid TLS configuration : {0}")]
+    Tls(#[from] rustls::Error)
+}
+
+impl TlsConfig {
+    fn load(&self) -> Result<ServerConfig, TlsConfigError> {
+        let certificates = CertificateDer::pem_file_iter(&self.certificate)?
+            .collect::<Result<Vec<_>, _>>()?;
+        let private_key = PrivateKeyDer::from_pem_file(&self.private_key)?;
+
+        Ok(ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certificates, private_key)?)
+    }
+}
+
+/// Holds one of the session slots until the session ends
+struct SessionSlot(Arc<AtomicUsize>);
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
-pub fn run_smtp_server(config: SmtpConfig, mail_sender_channel: Sender<SenderMsg>) {
+pub fn run_smtp_server(config: SmtpConfig, mail_sender_channel: Sender<SenderMsg>, spool: Arc<Spool>) {
 
     debug!("Starting SMTP server task");
 
@@ -17,31 +72,93 @@ pub fn run_smtp_server(config: SmtpConfig, mail_sender_channel: Sender<SenderMsg
 
     info!("SMTP listening on {}", config.bind);
 
+    let session_config = Arc::new(SessionConfig {
+        domain: "ddelivery".to_owned(),
+        node_eid: config.node_eid.clone(),
+        tls: config.tls.as_ref()
+            .map(|tls| tls.load().expect("Failed to load TLS configuration"))
+            .map(Arc::new),
+        require_tls: config.require_tls,
+        max_message_size: config.max_message_size,
+        auth: config.auth.as_ref()
+            .map(|auth| FileAuthBackend::load(&auth.credentials).expect("Failed to load SMTP credentials"))
+            .map(|backend| Arc::new(backend) as Arc<dyn AuthBackend>),
+        require_auth: config.auth.as_ref().is_some_and(|auth| auth.require_auth),
+        sender_policy: config.auth.as_ref().map_or(SenderPolicy::Any, |auth| auth.sender_policy),
+        directory: config.directory.as_ref()
+            .map(|directory| FileDirectory::load(directory).expect("Failed to load SMTP directory"))
+            .map(|directory| Arc::new(directory) as Arc<dyn Directory>),
+        verify_privacy: config.verify_privacy,
+        timeouts: config.timeouts
+    });
+
+    let active_sessions = Arc::new(AtomicUsize::new(0));
+
     for incoming in listener.incoming()
         .filter_map(|r| r.inspect_err(|e| error!("Failed to accept SMTP connection : {e}")).ok()) {
 
-        debug!("Connection started");
+        if active_sessions.load(Ordering::SeqCst) >= config.max_sessions {
+            warn!("Maximum of {} SMTP sessions reached, refusing connection", config.max_sessions);
+            if let Err(e) = Session::refuse(incoming, session_config.domain.clone()) {
+                error!("Failed to refuse SMTP connection : {e}");
+            }
+            continue;
+        }
+
+        active_sessions.fetch_add(1, Ordering::SeqCst);
+        let slot = SessionSlot(active_sessions.clone());
+        let mail_sender_channel = mail_sender_channel.clone();
+        let session_config = session_config.clone();
+        let spool = spool.clone();
+
+        thread::spawn(move || {
+            run_session(incoming, session_config, mail_sender_channel, &spool);
+            drop(slot);
+        });
+    }
+}
+
+fn run_session(incoming: TcpStream, config: Arc<SessionConfig>, mail_sender_channel: Sender<SenderMsg>, spool: &Arc<Spool>) {
+    debug!("Connection started");
 
-        let session = Session::new(incoming, "ddelivery".to_owned())
-            .unwrap();
+    let submission_timeout = config.timeouts.data_termination;
 
-        let Ok(mail_iter) = session.into_mail_iter() else {
+    let session = match Session::new(incoming, config) {
+        Ok(session) => session,
+        Err(e) => {
+            error!("Failed to start SMTP session : {e}");
             return;
-        };
-
-        for mail in mail_iter {
-            //TODO Make mail sending fail if bundle submission failed
-            match mail {
-                Ok(mail) => {
-                    debug!("Received email from {:?} to {:?}", mail.from, mail.receipients);
-                    if let Err(e) = mail_sender_channel.send(SenderMsg::SendMail(mail)){
-                        error!("Failed to send mail to sender task: {e}")
-                    }
-                },
-                Err(e) => error!("Failed to receive mail : {e}")
-            }
         }
+    };
+
+    let Ok(mut mail_iter) = session.into_mail_iter() else {
+        return;
+    };
+
+    while let Some(mail) = mail_iter.next() {
+        match mail {
+            Ok(mail) => {
+                debug!("Received email from {:?} to {:?}", mail.from, mail.receipients);
 
-        debug!("Connection ended")
+                let acknowledgement = match submit_mail(&mail_sender_channel, spool, mail, Some(submission_timeout)) {
+                    Ok(()) => mail_iter.accept(),
+                    Err(e @ SubmissionError::Spool(_)) => {
+                        error!("Failed to submit mail : {e}");
+                        mail_iter.defer_storage()
+                    },
+                    Err(e) => {
+                        error!("Failed to submit mail : {e}");
+                        mail_iter.defer("mail could not be submitted to the network".to_owned())
+                    }
+                };
+
+                if let Err(e) = acknowledgement {
+                    error!("Failed to acknowledge mail : {e}")
+                }
+            },
+            Err(e) => error!("Failed to receive mail : {e}")
+        }
     }
+
+    debug!("Connection ended")
 }
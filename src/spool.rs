@@ -0,0 +1,194 @@
+use std::{fs::{self, File}, io::{self, Write}, path::{Path, PathBuf}, process, sync::atomic::{AtomicU64, Ordering}, time::{SystemTime, UNIX_EPOCH}};
+
+use log::error;
+use thiserror::Error;
+
+use crate::envelope::{Envelope, EnvelopeError};
+
+const MESSAGE_EXTENSION: &str = "eml";
+const ENVELOPE_EXTENSION: &str = "envelope";
+
+#[derive(Debug, Error)]
+pub enum SpoolError {
+    #[error("Failed to access spool : {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid spooled envelope : {0}")]
+    Envelope(#[from] EnvelopeError)
+}
+
+/// Directory keeping messages across restarts until they are sent or delivered
+///
+/// Each message is stored as `<id>.eml` with its content and `<id>.envelope` with the
+/// recipients left, written last so that only complete messages are loaded. Messages
+/// that cannot be sent are moved to the dead-letter directory.
+pub struct Spool {
+    directory: PathBuf,
+    dead_letter_directory: PathBuf,
+    counter: AtomicU64
+}
+
+impl Spool {
+    pub fn open(directory: PathBuf, dead_letter_directory: PathBuf) -> Result<Self, SpoolError> {
+        fs::create_dir_all(&directory)?;
+        fs::create_dir_all(&dead_letter_directory)?;
+        Ok(Self { directory, dead_letter_directory, counter: AtomicU64::new(0) })
+    }
+
+    /// Durably store a message with its envelope, returning its identifier in the spool
+    pub fn store(&self, content: &[u8], envelope: &Envelope) -> Result<String, SpoolError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let id = format!("{now}.{}.{}", process::id(), self.counter.fetch_add(1, Ordering::SeqCst));
+
+        write_file(&self.path(&id, MESSAGE_EXTENSION), content)?;
+
+        if let Err(e) = self.update(&id, envelope) {
+            let _ = fs::remove_file(self.path(&id, MESSAGE_EXTENSION));
+            return Err(e);
+        }
+
+        Ok(id)
+    }
+
+    /// Record the envelope of a spooled message with the recipients still to be handled,
+    /// given without body as the message is stored apart
+    pub fn update(&self, id: &str, envelope: &Envelope) -> Result<(), SpoolError> {
+        Ok(write_file(&self.path(id, ENVELOPE_EXTENSION), &envelope.to_bytes())?)
+    }
+
+    /// Remove a message that was entirely handled
+    pub fn remove(&self, id: &str) -> Result<(), SpoolError> {
+        fs::remove_file(self.path(id, ENVELOPE_EXTENSION))?;
+        fs::remove_file(self.path(id, MESSAGE_EXTENSION))?;
+        Ok(())
+    }
+
+    /// Move a message that cannot be handled to the dead-letter directory
+    pub fn dead_letter(&self, id: &str) -> Result<(), SpoolError> {
+        for extension in [MESSAGE_EXTENSION, ENVELOPE_EXTENSION] {
+            fs::rename(self.path(id, extension), self.dead_letter_directory.join(format!("{id}.{extension}")))?;
+        }
+        Ok(())
+    }
+
+    /// Load the messages left in the spool by a previous run, oldest first, as their
+    /// identifier, the time they were stored and their envelope with the message as body
+    pub fn load_all(&self) -> Result<Vec<(String, SystemTime, Envelope)>, SpoolError> {
+        let mut ids = fs::read_dir(&self.directory)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && path.extension().is_some_and(|it| it == ENVELOPE_EXTENSION))
+            .filter_map(|path| path.file_stem().and_then(|it| it.to_str()).map(|it| it.to_owned()))
+            .collect::<Vec<_>>();
+        ids.sort_by_key(|id| id_order(id));
+
+        Ok(ids.into_iter()
+            .filter_map(|id| match self.load(&id) {
+                Ok((stored, envelope)) => Some((id, stored, envelope)),
+                Err(e) => {
+                    error!("Failed to load spooled message {id} : {e}");
+                    None
+                }
+            })
+            .collect())
+    }
+
+    fn load(&self, id: &str) -> Result<(SystemTime, Envelope), SpoolError> {
+        let mut envelope = Envelope::from_bytes(&fs::read(self.path(id, ENVELOPE_EXTENSION))?)?;
+        let message_path = self.path(id, MESSAGE_EXTENSION);
+        // The content is written once, its modification time is when it was stored
+        let stored = fs::metadata(&message_path)?.modified()?;
+        envelope.body = fs::read(message_path)?;
+        Ok((stored, envelope))
+    }
+
+    fn path(&self, id: &str, extension: &str) -> PathBuf {
+        self.directory.join(format!("{id}.{extension}"))
+    }
+}
+
+/// Order of spool identifiers `<secs>.<pid>.<counter>`, compared as numbers so that
+/// a tenth message of the same second comes after the second one
+fn id_order(id: &str) -> Vec<u64> {
+    id.split('.')
+        .map(|it| it.parse().unwrap_or(u64::MAX))
+        .collect()
+}
+
+/// Replace a file with a synced temporary file so that it is never left partially written
+fn write_file(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(".tmp");
+
+    let mut file = File::create(&temporary_path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+
+    fs::rename(&temporary_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::envelope::{EnvelopeRecipient, NotifyConditions};
+
+    fn envelope(addresses: &[&str]) -> Envelope {
+        Envelope {
+            from: Some("alice@node1".to_owned()),
+            envid: None,
+            return_full: false,
+            report_to: None,
+            recipients: addresses.iter()
+                .map(|address| EnvelopeRecipient {
+                    address: address.to_string(),
+                    notify: NotifyConditions::default(),
+                    orcpt: None,
+                    report_id: None
+                })
+                .collect(),
+            body: Vec::new()
+        }
+    }
+
+    fn open(name: &str) -> (PathBuf, Spool) {
+        let directory = std::env::temp_dir().join(format!("ddelivery-spool-{name}-{}", process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let spool = Spool::open(directory.join("spool"), directory.join("dead-letter")).unwrap();
+        (directory, spool)
+    }
+
+    #[test]
+    fn messages_are_loaded_back_in_order_with_recipients_left() {
+        let (directory, spool) = open("load");
+
+        let ids = (0..12)
+            .map(|i| spool.store(format!("message {i}").as_bytes(), &envelope(&["bob@node2", "carol@node3"])).unwrap())
+            .collect::<Vec<_>>();
+        spool.update(&ids[1], &envelope(&["carol@node3"])).unwrap();
+        spool.remove(&ids[2]).unwrap();
+
+        let loaded = spool.load_all().unwrap();
+        assert_eq!(loaded.iter().map(|(id, _, _)| id).collect::<Vec<_>>(),
+            ids.iter().enumerate().filter(|(i, _)| *i != 2).map(|(_, id)| id).collect::<Vec<_>>());
+
+        let (_, _, envelope) = &loaded[1];
+        assert_eq!(envelope.body, b"message 1");
+        assert_eq!(envelope.recipients.iter().map(|it| it.address.as_str()).collect::<Vec<_>>(), ["carol@node3"]);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn dead_letters_are_not_loaded() {
+        let (directory, spool) = open("dead-letter");
+
+        let id = spool.store(b"message", &envelope(&["bob@node2"])).unwrap();
+        spool.dead_letter(&id).unwrap();
+
+        assert!(spool.load_all().unwrap().is_empty());
+        assert!(directory.join("dead-letter").join(format!("{id}.eml")).is_file());
+        assert!(directory.join("dead-letter").join(format!("{id}.envelope")).is_file());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}